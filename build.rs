@@ -0,0 +1,26 @@
+use std::{env, process::Command};
+
+fn main() {
+    let revision = command_output("git", &["rev-parse", "--short", "HEAD"]);
+    println!("cargo:rustc-env=BUILD_REVISION={revision}");
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"]);
+    println!("cargo:rustc-env=BUILD_RUSTC_VERSION={rustc_version}");
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
+
+/// Returns trimmed stdout of the command or `unknown` if it can't be run,
+/// which happens when building from a source tarball without git.
+fn command_output(program: &str, args: &[&str]) -> String {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
@@ -0,0 +1,436 @@
+use std::{
+    fs,
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use md5::{Digest as _, Md5};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    client::{Client, EnvoyTls},
+    config::{missing_argument, secret, EnlightenArgs, EnvoyArgs, DEFAULT_ENVOY_TIMEOUT_SECONDS},
+    unix_time,
+};
+
+/// Limits for the delay between failed attempts to get a token from Enlighten.
+const AUTH_BACKOFF_MIN: Duration = Duration::from_secs(10);
+const AUTH_BACKOFF_MAX: Duration = Duration::from_secs(3600);
+
+/// Obtains a token for use with `--envoy.token` or `--envoy.token-file`.
+pub(crate) async fn print_token(credentials: Credentials) {
+    // Only Enlighten is contacted, so the Envoy address is not needed.
+    let client = Client::new(
+        "",
+        Auth::Enlighten(credentials.clone()),
+        EnvoyTls::Any,
+        None,
+        Duration::from_secs_f64(DEFAULT_ENVOY_TIMEOUT_SECONDS),
+        0,
+    )
+    .unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(1);
+    });
+
+    let token = match client.authenticate(&credentials).await {
+        Ok(token) => token,
+        Err(err) => {
+            eprintln!("error obtaining token: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    println!("{token}");
+
+    if let Some(expiry) = jwt_expiry(&token) {
+        let days = (expiry - unix_time() as i64) / 86400;
+        eprintln!("token expires at {expiry} (in {days} days)");
+    }
+}
+
+/// Doubles the delay after every failed authentication up to the limit,
+/// randomizing it so that exporters restarted together spread out.
+pub(crate) fn auth_backoff(failures: u32) -> Duration {
+    let backoff = AUTH_BACKOFF_MIN
+        .saturating_mul(2u32.saturating_pow(failures))
+        .min(AUTH_BACKOFF_MAX);
+
+    // Sub-second part of the current time is random enough for jitter.
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is before unix epoch")
+        .subsec_nanos();
+
+    backoff / 2 + backoff.mul_f64(nanos as f64 / 2e9)
+}
+
+#[derive(Clone)]
+pub(crate) enum Auth {
+    /// Token is obtained from Enlighten and renewed when needed.
+    Enlighten(Credentials),
+    /// Token is provided by the user and is never renewed.
+    Token(String),
+    /// Token is provided in a file, which is re-read when it changes.
+    TokenFile(Arc<TokenFile>),
+    /// Firmware before 7 has no tokens and uses digest auth with local users.
+    Digest { username: String, password: String },
+}
+
+#[derive(Clone)]
+pub(crate) struct Credentials {
+    pub(crate) username: String,
+    pub(crate) password: String,
+    pub(crate) serial_num: String,
+    pub(crate) enlighten: EnlightenArgs,
+}
+
+pub(crate) struct TokenFile {
+    path: PathBuf,
+    modified: std::sync::Mutex<Option<SystemTime>>,
+}
+
+impl TokenFile {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            modified: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Returns the token if the file changed since the last read.
+    pub(crate) fn reload(&self) -> Result<Option<String>, String> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
+            .map_err(|err| format!("error checking token file {}: {err}", self.path.display()))?;
+
+        let mut last_modified = self.modified.lock().expect("token file lock poisoned");
+        if *last_modified == Some(modified) {
+            return Ok(None);
+        }
+
+        let token = fs::read_to_string(&self.path)
+            .map_err(|err| format!("error reading token file {}: {err}", self.path.display()))?;
+
+        last_modified.replace(modified);
+
+        Ok(Some(token.trim().to_string()))
+    }
+}
+
+/// Credentials are only needed without a token. Besides flags and files
+/// they can come from systemd credentials, so clap can't require them.
+/// In local only mode there's no way to get a token, so one is required.
+pub(crate) fn envoy_auth(
+    envoy: &EnvoyArgs,
+    enlighten: &EnlightenArgs,
+    local_only: bool,
+) -> Result<Auth, String> {
+    let auth = match (
+        envoy.envoy_token.clone(),
+        envoy.envoy_token_file.clone(),
+        envoy.envoy_digest_username.clone(),
+    ) {
+        (Some(token), _, _) => Auth::Token(token),
+        (None, Some(path), _) => {
+            fs::metadata(&path)
+                .map_err(|err| format!("error reading token file {}: {err}", path.display()))?;
+
+            Auth::TokenFile(Arc::new(TokenFile::new(path)))
+        }
+        (None, None, Some(username)) => Auth::Digest {
+            username,
+            password: envoy
+                .envoy_digest_password
+                .clone()
+                .ok_or_else(|| missing_argument("--envoy.digest-password"))?,
+        },
+        (None, None, None) if local_only => {
+            return Err(
+                "--envoy.local-only requires --envoy.token, --envoy.token-file \
+                        or --envoy.digest-username"
+                    .to_string(),
+            )
+        }
+        (None, None, None) => Auth::Enlighten(credentials(envoy, enlighten)?),
+    };
+
+    Ok(auth)
+}
+
+/// Enlighten credentials from flags, files or systemd credentials.
+pub(crate) fn credentials(
+    envoy: &EnvoyArgs,
+    enlighten: &EnlightenArgs,
+) -> Result<Credentials, String> {
+    Ok(Credentials {
+        username: envoy
+            .envoy_username
+            .clone()
+            .map_or_else(
+                || secret(envoy.envoy_username_file.clone(), "envoy.username"),
+                |username| Ok(Some(username)),
+            )?
+            .ok_or_else(|| missing_argument("--envoy.username"))?,
+        password: envoy
+            .envoy_password
+            .clone()
+            .map_or_else(
+                || secret(envoy.envoy_password_file.clone(), "envoy.password"),
+                |password| Ok(Some(password)),
+            )?
+            .ok_or_else(|| missing_argument("--envoy.password"))?,
+        serial_num: envoy
+            .envoy_serial
+            .clone()
+            .ok_or_else(|| missing_argument("--envoy.serial"))?,
+        enlighten: enlighten.clone(),
+    })
+}
+
+/// Reads a previously cached token, ignoring it if it has already expired.
+pub(crate) fn load_cached_token(path: &Path) -> Option<String> {
+    let token = match fs::read_to_string(path) {
+        Ok(token) => token.trim().to_string(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            eprintln!("error reading cached token from {}: {err}", path.display());
+            return None;
+        }
+    };
+
+    match jwt_expiry(&token) {
+        Some(expiry) if expiry as f64 > unix_time() => Some(token),
+        _ => None,
+    }
+}
+
+/// Writes the token to a file that is only readable by the current user.
+pub(crate) fn store_cached_token(path: &Path, token: &str) -> std::io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+
+    // The mode only applies to new files, existing ones could be readable by others.
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+
+    file.write_all(token.as_bytes())
+}
+
+/// Digest challenge from the Envoy, which is answered until the Envoy
+/// rejects the nonce, so that every request doesn't need a round trip.
+pub(crate) struct DigestChallenge {
+    realm: String,
+    pub(crate) nonce: String,
+    qop_auth: bool,
+    opaque: Option<String>,
+    /// Number of requests made with the nonce so far.
+    count: u32,
+}
+
+impl DigestChallenge {
+    pub(crate) fn parse(challenge: &str) -> Option<Self> {
+        let params = challenge.strip_prefix("Digest ")?;
+
+        let mut realm = None;
+        let mut nonce = None;
+        let mut qop = None;
+        let mut opaque = None;
+
+        // Parameters are comma separated, but quoted values can contain commas.
+        let mut rest = params.trim();
+        while !rest.is_empty() {
+            let (key, after) = rest.split_once('=')?;
+            let (value, after) = match after.strip_prefix('"') {
+                Some(quoted) => quoted.split_once('"')?,
+                None => after.split_once(',').unwrap_or((after, "")),
+            };
+
+            match key.trim() {
+                "realm" => realm = Some(value),
+                "nonce" => nonce = Some(value),
+                "qop" => qop = Some(value),
+                "opaque" => opaque = Some(value),
+                _ => {}
+            }
+
+            rest = after.trim_start_matches([',', ' ']);
+        }
+
+        Some(Self {
+            realm: realm?.to_string(),
+            nonce: nonce?.to_string(),
+            qop_auth: qop.is_some_and(|qop| qop.split(',').any(|qop| qop.trim() == "auth")),
+            opaque: opaque.map(str::to_string),
+            count: 0,
+        })
+    }
+
+    /// Builds the `Authorization` header for the next `GET` with the nonce.
+    pub(crate) fn authorization(&mut self, username: &str, password: &str, uri: &str) -> String {
+        let Self {
+            realm,
+            nonce,
+            qop_auth,
+            opaque,
+            count,
+        } = self;
+
+        let ha1 = md5_hex(&format!("{username}:{realm}:{password}"));
+        let ha2 = md5_hex(&format!("GET:{uri}"));
+
+        let mut header = format!(
+            r#"Digest username="{username}", realm="{realm}", nonce="{nonce}", uri="{uri}""#
+        );
+
+        if *qop_auth {
+            *count += 1;
+            let nc = format!("{count:08x}");
+            let cnonce = format!("{:016x}", unix_time().to_bits());
+            let response = md5_hex(&format!("{ha1}:{nonce}:{nc}:{cnonce}:auth:{ha2}"));
+            header.push_str(&format!(
+                r#", qop=auth, nc={nc}, cnonce="{cnonce}", response="{response}""#
+            ));
+        } else {
+            let response = md5_hex(&format!("{ha1}:{nonce}:{ha2}"));
+            header.push_str(&format!(r#", response="{response}""#));
+        }
+
+        if let Some(opaque) = opaque {
+            header.push_str(&format!(r#", opaque="{opaque}""#));
+        }
+
+        header
+    }
+}
+
+fn md5_hex(data: &str) -> String {
+    format!("{:x}", Md5::digest(data))
+}
+
+/// Extracts the `exp` claim from the JWT payload.
+pub(crate) fn jwt_expiry(token: &str) -> Option<i64> {
+    jwt_claims(token).map(|claims| claims.exp)
+}
+
+/// Decodes the JWT payload without verifying the signature.
+pub(crate) fn jwt_claims(token: &str) -> Option<JwtClaims> {
+    let payload = token.split('.').nth(1)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+
+    serde_json::from_slice::<JwtClaims>(&payload).ok()
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct JwtClaims {
+    exp: i64,
+    #[serde(rename = "enphaseUser")]
+    pub(crate) enphase_user: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct LoginResponse {
+    pub(crate) session_id: String,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct TokenRequest {
+    pub(crate) session_id: String,
+    pub(crate) username: String,
+    pub(crate) serial_num: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_authorization() {
+        // Example from RFC 2069.
+        let mut challenge = DigestChallenge::parse(
+            r#"Digest realm="testrealm@host.com", nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", opaque="5ccc069c403ebaf9f0171e9517f40e41""#,
+        )
+        .unwrap();
+
+        assert!(!challenge.qop_auth);
+        assert_eq!(
+            challenge.authorization("Mufasa", "Circle Of Life", "/dir/index.html"),
+            r#"Digest username="Mufasa", realm="testrealm@host.com", nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", uri="/dir/index.html", response="670fd8c2df070c60b045671b8b24ff02", opaque="5ccc069c403ebaf9f0171e9517f40e41""#
+        );
+
+        let mut challenge = DigestChallenge::parse(
+            r#"Digest realm="enphaseenergy.com", nonce="abc", qop="auth,auth-int""#,
+        )
+        .unwrap();
+
+        assert!(challenge
+            .authorization("installer", "", "/")
+            .contains("nc=00000001"));
+        assert!(challenge
+            .authorization("installer", "", "/")
+            .contains("nc=00000002"));
+
+        assert!(DigestChallenge::parse(r#"Basic realm="enphaseenergy.com""#).is_none());
+        assert!(DigestChallenge::parse(r#"Digest realm="enphaseenergy.com""#).is_none());
+    }
+
+    #[test]
+    fn jwt_expiry_claim() {
+        let token = |claims: &str| {
+            format!(
+                "eyJhbGciOiJFUzI1NiJ9.{}.c2ln",
+                URL_SAFE_NO_PAD.encode(claims)
+            )
+        };
+
+        assert_eq!(
+            jwt_expiry(&token(r#"{"exp": 1735689600, "enphaseUser": "owner"}"#)),
+            Some(1735689600)
+        );
+        assert_eq!(jwt_expiry(&token(r#"{"enphaseUser": "owner"}"#)), None);
+        assert_eq!(jwt_expiry("not a token"), None);
+        assert_eq!(jwt_expiry("header.!!!.signature"), None);
+
+        let claims = jwt_claims(&token(r#"{"exp": 1735689600, "enphaseUser": "installer"}"#));
+        assert_eq!(claims.unwrap().enphase_user.as_deref(), Some("installer"));
+    }
+
+    #[test]
+    fn cached_token_permissions() {
+        let path = std::env::temp_dir().join(format!("enphase_envoy_token_{}", std::process::id()));
+
+        fs::write(&path, "stale").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        store_cached_token(&path, "token").unwrap();
+
+        let metadata = fs::metadata(&path).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(contents, "token");
+    }
+
+    #[test]
+    fn auth_backoff_is_limited() {
+        for failures in 0..40 {
+            let backoff = auth_backoff(failures);
+            let limit = AUTH_BACKOFF_MIN
+                .saturating_mul(2u32.saturating_pow(failures))
+                .min(AUTH_BACKOFF_MAX);
+
+            assert!(
+                backoff >= limit / 2 && backoff <= limit,
+                "{failures}: {backoff:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,1085 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use prometheus_client::metrics::{
+    counter::Counter,
+    histogram::{exponential_buckets, Histogram},
+};
+use reqwest::{
+    header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+    multipart::Form,
+    Proxy, StatusCode,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, DigitallySignedStruct, SignatureScheme,
+};
+use serde::de::DeserializeOwned;
+use tokio::{sync::watch, time::sleep};
+
+use crate::{
+    auth::{
+        auth_backoff, jwt_claims, jwt_expiry, load_cached_token, store_cached_token, Auth,
+        Credentials, DigestChallenge, LoginResponse, TokenRequest,
+    },
+    collectors::{
+        xml_tag, BatteryPower, CumulativeProductionResponse, DateTime, DateTimeResponse,
+        DeviceData, DeviceStatusResponse, DryContact, DryContactSettings, DryContactsResponse,
+        EndpointLabels, EnpowerRelays, EnsembleInventory, EnsemblePowerResponse, EnvoyInfo, Event,
+        EventsResponse, ExportLimitSettings, Generator, GridProfileLabels, GridProfileResponse,
+        HomeResponse, HttpErrorLabels, InfoLabels, InvalidResponseLabels, Inventory,
+        InverterProduction, InverterStatus, LivedataResponse, Meter, MeterReading, MeterReport,
+        PowerMode, ProductionSummary, SecurityControl, StormGuard, Tariff, TariffResponse,
+        ENVOY_EID,
+    },
+    config::{EnlightenArgs, EnvoyArgs, TokenScope},
+    metrics::Family,
+    unix_time,
+};
+
+/// Delay before the first retry of a failed request to the Envoy, doubled after that.
+const ENVOY_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Ideally we'd use [enphase](https://docs.rs/enphase/) crate, but it relies
+/// on valid TLS certificates, while Enphase self-signs theirs for Envoy.
+#[derive(Clone)]
+pub(crate) struct Client {
+    /// Scheme, host and port of the Envoy, without the trailing slash.
+    pub(crate) base_url: String,
+    pub(crate) auth: Auth,
+    client: reqwest::Client,
+    auth_client: reqwest::Client,
+    retries: u32,
+    token: watch::Receiver<Option<String>>,
+    token_sender: Arc<watch::Sender<Option<String>>>,
+    rejected_token: Arc<watch::Sender<Option<String>>>,
+    token_cache_file: Option<PathBuf>,
+    digest_challenge: Arc<std::sync::Mutex<Option<DigestChallenge>>>,
+    pub(crate) auth_refreshes: Counter,
+    pub(crate) auth_failures: Counter,
+    pub(crate) http_errors: Family<HttpErrorLabels, Counter>,
+    pub(crate) invalid_responses: Family<InvalidResponseLabels, Counter>,
+    pub(crate) request_duration_seconds: Family<EndpointLabels, Histogram>,
+}
+
+impl Client {
+    pub(crate) fn new(
+        base_url: impl AsRef<str>,
+        auth: Auth,
+        tls: EnvoyTls,
+        token_cache_file: Option<PathBuf>,
+        timeout: Duration,
+        retries: u32,
+    ) -> Result<Self, String> {
+        let base_url = base_url.as_ref().into();
+
+        // Envoys have self-signed certificates, which can only be verified
+        // if the certificate is provided and the address matches its name,
+        // or if the certificate is pinned by its fingerprint.
+        let client = match tls {
+            EnvoyTls::Any => reqwest::ClientBuilder::new().danger_accept_invalid_certs(true),
+            EnvoyTls::Certificate(certificate) => reqwest::ClientBuilder::new()
+                .tls_built_in_root_certs(false)
+                .add_root_certificate(certificate),
+            EnvoyTls::Fingerprint(fingerprint) => {
+                let mut config = rustls::ClientConfig::builder()
+                    .with_root_certificates(rustls::RootCertStore::empty())
+                    .with_no_client_auth();
+
+                config
+                    .dangerous()
+                    .set_certificate_verifier(Arc::new(FingerprintVerifier::new(fingerprint)));
+
+                reqwest::ClientBuilder::new().use_preconfigured_tls(config)
+            }
+        };
+
+        // The Envoy is on the local network, so proxies from the environment
+        // are only used for Enlighten and Entrez.
+        let client = client
+            .no_proxy()
+            .connect_timeout(timeout)
+            .timeout(timeout)
+            .build()
+            .map_err(|err| format!("error building http client: {err}"))?;
+
+        let mut auth_client = reqwest::ClientBuilder::new()
+            .connect_timeout(timeout)
+            .timeout(timeout);
+
+        if let Auth::Enlighten(Credentials {
+            enlighten:
+                EnlightenArgs {
+                    proxy_url: Some(proxy_url),
+                    ..
+                },
+            ..
+        }) = &auth
+        {
+            let proxy = Proxy::all(proxy_url.clone())
+                .map_err(|err| format!("invalid --auth.proxy-url {proxy_url}: {err}"))?;
+
+            auth_client = auth_client.proxy(proxy);
+        }
+
+        let auth_client = auth_client
+            .build()
+            .map_err(|err| format!("error building http client: {err}"))?;
+
+        let token = match &auth {
+            Auth::Enlighten(_) => token_cache_file.as_deref().and_then(load_cached_token),
+            Auth::Token(token) => Some(token.clone()),
+            Auth::TokenFile(file) => file.reload()?,
+            Auth::Digest { .. } => None,
+        };
+
+        let (token_sender, token) = watch::channel(token);
+        let (rejected_token, _) = watch::channel(None);
+
+        Ok(Self {
+            base_url,
+            auth,
+            client,
+            auth_client,
+            retries,
+            token,
+            token_sender: Arc::new(token_sender),
+            rejected_token: Arc::new(rejected_token),
+            token_cache_file,
+            digest_challenge: Arc::default(),
+            auth_refreshes: Counter::default(),
+            auth_failures: Counter::default(),
+            http_errors: Family::default(),
+            invalid_responses: Family::default(),
+            request_duration_seconds: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.05, 2.0, 10))
+            }),
+        })
+    }
+
+    /// Shares metrics with the client being replaced, as only its metrics
+    /// are registered.
+    pub(crate) fn with_metrics_of(self, other: &Client) -> Self {
+        Self {
+            auth_refreshes: other.auth_refreshes.clone(),
+            auth_failures: other.auth_failures.clone(),
+            http_errors: other.http_errors.clone(),
+            invalid_responses: other.invalid_responses.clone(),
+            request_duration_seconds: other.request_duration_seconds.clone(),
+            ..self
+        }
+    }
+
+    pub(crate) async fn authenticate(&self, credentials: &Credentials) -> Result<String, Error> {
+        let form = Form::new()
+            .text("user[email]", credentials.username.clone())
+            .text("user[password]", credentials.password.clone());
+
+        let response = self
+            .auth_client
+            .post(format!(
+                "{}/login/login.json",
+                credentials.enlighten.enlighten_url.trim_end_matches('/')
+            ))
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let session_id = response.json::<LoginResponse>().await?.session_id;
+        let username = credentials.username.clone();
+        let serial_num = credentials.serial_num.clone();
+
+        let response = self
+            .auth_client
+            .post(format!(
+                "{}/tokens",
+                credentials.enlighten.entrez_url.trim_end_matches('/')
+            ))
+            .json(&TokenRequest {
+                session_id,
+                username,
+                serial_num,
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let token = response.bytes().await?;
+        let token = String::from_utf8_lossy(&token).to_string();
+
+        if credentials.enlighten.token_scope == TokenScope::Installer {
+            let user = jwt_claims(&token).and_then(|claims| claims.enphase_user);
+
+            if user.as_deref() != Some("installer") {
+                return Err(Error::TokenScope(user.unwrap_or_default()));
+            }
+        }
+
+        Ok(token)
+    }
+
+    /// Returns the current token, which is maintained by [`Client::maintain_token`].
+    pub(crate) fn token(&self) -> Result<String, Error> {
+        self.token.borrow().clone().ok_or(Error::NoToken)
+    }
+
+    /// Keeps the token valid, so that scrapes never wait for authentication.
+    /// Tokens from Enlighten are renewed before they expire or once the Envoy
+    /// rejects them, token files are checked for changes periodically.
+    pub(crate) async fn maintain_token(self, renew_before_seconds: i64) {
+        let mut rejected = self.rejected_token.subscribe();
+        let mut failures = 0;
+        let mut renewed = false;
+
+        loop {
+            let credentials = match &self.auth {
+                Auth::Enlighten(credentials) => credentials,
+                Auth::TokenFile(file) => {
+                    // Read errors keep the previous token, as the file might be mid-rotation.
+                    match file.reload() {
+                        Ok(Some(token)) => {
+                            self.token_sender.send_replace(Some(token));
+                        }
+                        Ok(None) => {}
+                        Err(err) => eprintln!("{err}"),
+                    }
+
+                    sleep(Duration::from_secs(10)).await;
+                    continue;
+                }
+                Auth::Token(_) | Auth::Digest { .. } => return,
+            };
+
+            let current = self.token.borrow().clone();
+
+            // Tokens that were given or cached are renewed right away once they
+            // are due. Renewed tokens are kept for at least half of their remaining
+            // lifetime, so that short lived tokens are not renewed in a loop.
+            let delay = match current.as_deref().map(jwt_expiry) {
+                None => 0,
+                Some(None) => 86400,
+                Some(Some(expiry)) => {
+                    let remaining = expiry - unix_time() as i64;
+                    if remaining <= 0 || (!renewed && remaining <= renew_before_seconds) {
+                        0
+                    } else {
+                        (remaining - renew_before_seconds)
+                            .max(remaining / 2)
+                            .max(60)
+                    }
+                }
+            };
+
+            tokio::select! {
+                _ = sleep(Duration::from_secs(delay as u64)) => {}
+                _ = rejected.changed() => {
+                    // Requests that started with an older token can still fail.
+                    if *rejected.borrow_and_update() != current {
+                        continue;
+                    }
+                }
+            }
+
+            match self.renew_token(credentials).await {
+                Ok(()) => {
+                    failures = 0;
+                    renewed = true;
+                }
+                Err(err) => {
+                    self.auth_failures.inc();
+
+                    let backoff = auth_backoff(failures);
+                    failures += 1;
+
+                    eprintln!("error obtaining token, retrying in {backoff:?}: {err}");
+                    sleep(backoff).await;
+                }
+            }
+        }
+    }
+
+    /// Obtains a new token from Enlighten and starts using it once the Envoy accepts it.
+    async fn renew_token(&self, credentials: &Credentials) -> Result<(), Error> {
+        let token = self.authenticate(credentials).await?;
+
+        self.check_token(&token).await?;
+
+        self.auth_refreshes.inc();
+
+        if let Some(path) = &self.token_cache_file {
+            if let Err(err) = store_cached_token(path, &token) {
+                eprintln!("error caching token in {}: {err}", path.display());
+            }
+        }
+
+        self.token_sender.send_replace(Some(token));
+
+        Ok(())
+    }
+
+    /// Authenticates if there's no token yet and makes one authenticated request,
+    /// so that misconfiguration is reported at startup rather than on scrapes.
+    pub(crate) async fn check_connectivity(&self) -> Result<(), Error> {
+        if let Auth::Enlighten(credentials) = &self.auth {
+            if self.token.borrow().is_none() {
+                self.renew_token(credentials).await?;
+            }
+        }
+
+        self.production_summary().await.map(|_| ())
+    }
+
+    /// Authenticates if there's no token yet or checks that the Envoy accepts
+    /// the token that was provided.
+    pub(crate) async fn check_auth(&self) -> Result<(), Error> {
+        let token = self.token.borrow().clone();
+
+        match (&self.auth, token) {
+            (Auth::Digest { .. }, _) => Ok(()),
+            (Auth::Enlighten(credentials), None) => self.renew_token(credentials).await,
+            (_, Some(token)) => self.check_token(&token).await,
+            (_, None) => Err(Error::NoToken),
+        }
+    }
+
+    /// Makes sure the Envoy accepts a fresh token before it's used and cached.
+    /// Only a successful response accepts the token, so that a flaky Envoy
+    /// doesn't let an expired or revoked token through. Connection errors
+    /// and 5xx are retried like other requests before giving up.
+    async fn check_token(&self, token: &str) -> Result<(), Error> {
+        let response = self
+            .send(
+                "/auth/check_jwt",
+                self.client
+                    .get(format!("{}/auth/check_jwt", self.base_url))
+                    .bearer_auth(token),
+            )
+            .await?;
+
+        match response.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(Error::TokenRejected),
+            _ => {
+                response.error_for_status()?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Asks for a new token after the Envoy rejected the current one and
+    /// returns the token if it was already renewed by another request. The
+    /// renewal isn't waited for, as getting a token from Enlighten can take
+    /// longer than Prometheus waits for the scrape, so the next scrape uses it.
+    fn renewed_token(&self, rejected: &str) -> Option<String> {
+        self.rejected_token.send_replace(Some(rejected.to_string()));
+
+        self.token
+            .borrow()
+            .clone()
+            .filter(|token| token != rejected)
+    }
+
+    /// The info endpoint is XML and doesn't require authentication.
+    pub(crate) async fn info(&self) -> Result<EnvoyInfo, Error> {
+        let body = self
+            .send("/info", self.client.get(format!("{}/info", self.base_url)))
+            .await?;
+
+        let body = self.check_status(body)?.text().await?;
+
+        let tag = |name: &str| xml_tag(&body, name).unwrap_or_default().to_string();
+
+        Ok(EnvoyInfo {
+            labels: InfoLabels {
+                serial_num: tag("sn"),
+                part_num: tag("pn"),
+                software_version: tag("software"),
+            },
+            time: tag("time").parse().ok(),
+        })
+    }
+
+    pub(crate) async fn date_time(&self) -> Result<Option<DateTime>, Error> {
+        self.get_optional::<DateTimeResponse>("/admin/lib/date_time_display.json")
+            .await
+            .map(|response| response.map(|response| response.date_time))
+    }
+
+    pub(crate) async fn home(&self) -> Result<HomeResponse, Error> {
+        self.get::<HomeResponse>("/home.json").await
+    }
+
+    /// Returns expiration time of the current token.
+    pub(crate) fn token_expiry(&self) -> Option<i64> {
+        self.token.borrow().as_deref().and_then(jwt_expiry)
+    }
+
+    pub(crate) async fn production_report(&self) -> Result<MeterReport, Error> {
+        self.get::<MeterReport>("/ivp/meters/reports/production")
+            .await
+    }
+
+    pub(crate) async fn inverter_production_watts(&self) -> Result<Vec<InverterProduction>, Error> {
+        self.get::<Vec<InverterProduction>>("/api/v1/production/inverters")
+            .await
+    }
+
+    pub(crate) async fn production_summary(&self) -> Result<ProductionSummary, Error> {
+        self.get::<ProductionSummary>("/api/v1/production").await
+    }
+
+    pub(crate) async fn grid_profile(&self) -> Result<Option<GridProfileLabels>, Error> {
+        self.get_installer::<GridProfileResponse>("/installer/agf/index.json")
+            .await
+            .map(|response| {
+                response.map(|response| {
+                    // The selected profile looks like `IEEE 1547:2018:1.2.4`,
+                    // where the last component is the version.
+                    let selected = response.selected_profile;
+                    let (name, version) = selected.rsplit_once(':').unwrap_or((&selected, ""));
+
+                    GridProfileLabels {
+                        name: name.to_string(),
+                        version: version.to_string(),
+                    }
+                })
+            })
+    }
+
+    pub(crate) async fn export_limit(&self) -> Result<Option<ExportLimitSettings>, Error> {
+        self.get_installer::<ExportLimitSettings>("/ivp/ss/pel_settings")
+            .await
+    }
+
+    pub(crate) async fn power_mode(&self) -> Result<Option<PowerMode>, Error> {
+        self.get_optional::<PowerMode>(&format!("/ivp/mod/{ENVOY_EID}/mode/power"))
+            .await
+    }
+
+    pub(crate) async fn inverter_lifetime_watt_hours(&self) -> Result<Vec<(String, f64)>, Error> {
+        let Some(response) = self
+            .get_installer::<HashMap<String, serde_json::Value>>("/ivp/pdm/device_data")
+            .await?
+        else {
+            return Ok(vec![]);
+        };
+
+        // Besides devices keyed by their id there are summary entries,
+        // which don't parse as devices and are skipped.
+        Ok(response
+            .into_values()
+            .filter_map(|value| serde_json::from_value::<DeviceData>(value).ok())
+            .filter(|device| device.dev_name == "pcu")
+            .filter_map(|device| {
+                let joules = device.channels.first()?.lifetime.joules_produced;
+                Some((device.serial_num, joules / 3600.0))
+            })
+            .collect())
+    }
+
+    pub(crate) async fn inverter_status(&self) -> Result<Vec<InverterStatus>, Error> {
+        let Some(response) = self
+            .get_installer::<DeviceStatusResponse>("/ivp/peb/devstatus")
+            .await?
+        else {
+            return Ok(vec![]);
+        };
+
+        Ok(response
+            .pcu
+            .rows()
+            .filter_map(|row| {
+                Some(InverterStatus {
+                    serial_num: row.get("serialNumber")?.as_str()?.to_string(),
+                    dc_voltage: row.get("dcVoltageINmV")?.as_f64()? / 1000.0,
+                    dc_current: row.get("dcCurrentINmA")?.as_f64()? / 1000.0,
+                    temperature: row.get("temperatureINDegC")?.as_f64()?,
+                })
+            })
+            .collect())
+    }
+
+    pub(crate) async fn meters(&self) -> Result<Vec<Meter>, Error> {
+        self.get::<Vec<Meter>>("/ivp/meters").await
+    }
+
+    pub(crate) async fn meter_readings(&self) -> Result<Vec<MeterReading>, Error> {
+        self.get::<Vec<MeterReading>>("/ivp/meters/readings").await
+    }
+
+    pub(crate) async fn consumption_reports(&self) -> Result<Vec<MeterReport>, Error> {
+        self.get::<Vec<MeterReport>>("/ivp/meters/reports/consumption")
+            .await
+    }
+
+    pub(crate) async fn cumulative_production(
+        &self,
+    ) -> Result<CumulativeProductionResponse, Error> {
+        self.get::<CumulativeProductionResponse>("/production.json")
+            .await
+    }
+
+    pub(crate) async fn inventory(&self) -> Result<Vec<Inventory>, Error> {
+        self.get::<Vec<Inventory>>("/inventory.json").await
+    }
+
+    pub(crate) async fn events(&self) -> Result<Vec<Event>, Error> {
+        self.get_optional::<EventsResponse>("/datatab/event_dt.rb")
+            .await
+            .map(|response| {
+                response
+                    .map(|response| {
+                        response
+                            .data
+                            .into_iter()
+                            .filter_map(Event::from_row)
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            })
+    }
+
+    pub(crate) async fn tariff(&self) -> Result<Option<Tariff>, Error> {
+        self.get_optional::<TariffResponse>("/admin/lib/tariff")
+            .await
+            .map(|response| response.map(|response| response.tariff))
+    }
+
+    pub(crate) async fn livedata(&self) -> Result<Option<LivedataResponse>, Error> {
+        self.get_optional::<LivedataResponse>("/ivp/livedata/status")
+            .await
+    }
+
+    pub(crate) async fn ensemble_inventory(&self) -> Result<Vec<EnsembleInventory>, Error> {
+        self.get_optional::<Vec<EnsembleInventory>>("/ivp/ensemble/inventory")
+            .await
+            .map(|response| response.unwrap_or_default())
+    }
+
+    pub(crate) async fn dry_contacts(&self) -> Result<Vec<DryContact>, Error> {
+        self.get_optional::<DryContactsResponse<DryContact>>("/ivp/ensemble/dry_contacts")
+            .await
+            .map(|response| {
+                response
+                    .map(|response| response.dry_contacts)
+                    .unwrap_or_default()
+            })
+    }
+
+    pub(crate) async fn dry_contact_settings(&self) -> Result<Vec<DryContactSettings>, Error> {
+        self.get_optional::<DryContactsResponse<DryContactSettings>>("/ivp/ss/dry_contact_settings")
+            .await
+            .map(|response| {
+                response
+                    .map(|response| response.dry_contacts)
+                    .unwrap_or_default()
+            })
+    }
+
+    pub(crate) async fn generator(&self) -> Result<Option<Generator>, Error> {
+        self.get_optional::<Generator>("/ivp/ensemble/generator")
+            .await
+    }
+
+    pub(crate) async fn storm_guard(&self) -> Result<Option<StormGuard>, Error> {
+        self.get_optional::<StormGuard>("/ivp/ss/stormguard").await
+    }
+
+    pub(crate) async fn security_control(&self) -> Result<Option<SecurityControl>, Error> {
+        self.get_optional::<SecurityControl>("/ivp/ensemble/secctrl")
+            .await
+    }
+
+    pub(crate) async fn enpower_relays(&self) -> Result<Option<EnpowerRelays>, Error> {
+        self.get_optional::<EnpowerRelays>("/ivp/ensemble/relay")
+            .await
+    }
+
+    pub(crate) async fn battery_power(&self) -> Result<Vec<BatteryPower>, Error> {
+        self.get_optional::<EnsemblePowerResponse>("/ivp/ensemble/power")
+            .await
+            .map(|response| {
+                response
+                    .map(|response| response.devices)
+                    .unwrap_or_default()
+            })
+    }
+
+    /// Same as [`Client::get`], but treats 404 as a missing endpoint, which is
+    /// how the Envoy responds when the relevant hardware is not installed.
+    async fn get_optional<R>(&self, path: &str) -> Result<Option<R>, Error>
+    where
+        R: DeserializeOwned,
+    {
+        match self.get::<R>(path).await {
+            Ok(response) => Ok(Some(response)),
+            Err(err) if err.status() == Some(StatusCode::NOT_FOUND) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Same as [`Client::get_optional`], but for endpoints that are only
+    /// available with an installer token. The token is not renewed on 401,
+    /// as that is what owner tokens get.
+    async fn get_installer<R>(&self, path: &str) -> Result<Option<R>, Error>
+    where
+        R: DeserializeOwned,
+    {
+        match self.get_once::<R>(path).await {
+            Ok(response) => Ok(Some(response)),
+            Err(err) if err.status() == Some(StatusCode::NOT_FOUND) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Requests the path without renewing the token on 401.
+    pub(crate) async fn get_once<R>(&self, path: &str) -> Result<R, Error>
+    where
+        R: DeserializeOwned,
+    {
+        match &self.auth {
+            Auth::Digest { username, password } => {
+                self.get_with_digest::<R>(path, username, password).await
+            }
+            _ => {
+                let token = self.token()?;
+                self.get_with_token::<R>(path, &token).await
+            }
+        }
+    }
+
+    /// Requests the path, renewing the token on 401, which happens when the
+    /// token expires or the Envoy forgets it. The request is retried once
+    /// if the token was renewed in the meantime.
+    async fn get<R>(&self, path: &str) -> Result<R, Error>
+    where
+        R: DeserializeOwned,
+    {
+        if let Auth::Digest { username, password } = &self.auth {
+            return self.get_with_digest::<R>(path, username, password).await;
+        }
+
+        let token = self.token()?;
+
+        match self.get_with_token::<R>(path, &token).await {
+            Err(err)
+                if err.status() == Some(StatusCode::UNAUTHORIZED)
+                    && matches!(self.auth, Auth::Enlighten(_)) =>
+            {
+                match self.renewed_token(&token) {
+                    Some(token) => self.get_with_token::<R>(path, &token).await,
+                    None => Err(err),
+                }
+            }
+            result => result,
+        }
+    }
+
+    async fn get_with_token<R>(&self, path: &str, token: &str) -> Result<R, Error>
+    where
+        R: DeserializeOwned,
+    {
+        let response = self
+            .send(
+                path,
+                self.client
+                    .get(format!("{}{}", self.base_url, path))
+                    .bearer_auth(token),
+            )
+            .await?;
+
+        self.parse_json(path, response).await
+    }
+
+    /// Answers the last digest challenge, or requests the path without auth
+    /// to get one. A 401 means the nonce is stale, and the request is repeated
+    /// once with the new challenge.
+    async fn get_with_digest<R>(
+        &self,
+        path: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<R, Error>
+    where
+        R: DeserializeOwned,
+    {
+        let url = format!("{}{}", self.base_url, path);
+
+        let mut request = self.client.get(&url);
+        if let Some(challenge) = self.digest_challenge().as_mut() {
+            request = request.header(
+                AUTHORIZATION,
+                challenge.authorization(username, password, path),
+            );
+        }
+
+        let mut response = self.send(path, request).await?;
+
+        if response.status() == StatusCode::UNAUTHORIZED {
+            let challenge = response
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .and_then(|challenge| challenge.to_str().ok())
+                .and_then(DigestChallenge::parse);
+
+            let authorization = {
+                let mut cached = self.digest_challenge();
+                // Concurrent requests get the same nonce, which keeps its count.
+                if cached.as_ref().map(|cached| &cached.nonce)
+                    != challenge.as_ref().map(|challenge| &challenge.nonce)
+                {
+                    *cached = challenge;
+                }
+                cached
+                    .as_mut()
+                    .map(|challenge| challenge.authorization(username, password, path))
+            };
+
+            if let Some(authorization) = authorization {
+                response = self
+                    .send(
+                        path,
+                        self.client.get(&url).header(AUTHORIZATION, authorization),
+                    )
+                    .await?;
+            }
+        }
+
+        self.parse_json(path, response).await
+    }
+
+    fn digest_challenge(&self) -> std::sync::MutexGuard<'_, Option<DigestChallenge>> {
+        self.digest_challenge
+            .lock()
+            .expect("digest challenge lock poisoned")
+    }
+
+    /// Sends the request, retrying with backoff if the Envoy couldn't be reached
+    /// or responded with 5xx, which happens when its web server is waking up.
+    /// The duration of every attempt is observed separately, without backoff.
+    async fn send(
+        &self,
+        endpoint: &str,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let mut attempt = 0;
+
+        loop {
+            let start = Instant::now();
+
+            let result = match request.try_clone() {
+                Some(attempt_request) => attempt_request.send().await,
+                None => return self.observed(endpoint, start, request.send().await),
+            };
+
+            let result = self.observed(endpoint, start, result);
+
+            let retry = match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(err) => err.is_connect(),
+            };
+
+            if !retry || attempt >= self.retries {
+                return result;
+            }
+
+            sleep(ENVOY_RETRY_BACKOFF * 2u32.pow(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Records time from sending the request until response headers arrived,
+    /// unless the Envoy couldn't be reached.
+    fn observed(
+        &self,
+        endpoint: &str,
+        start: Instant,
+        result: Result<reqwest::Response, reqwest::Error>,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        if result.is_ok() {
+            self.request_duration_seconds
+                .get_or_create(&EndpointLabels {
+                    endpoint: endpoint.to_string(),
+                })
+                .observe(start.elapsed().as_secs_f64());
+        }
+
+        result
+    }
+
+    /// The Envoy responds with an HTML page instead of JSON when it doesn't like
+    /// the token, which is reported as such instead of as a syntax error.
+    async fn parse_json<R>(&self, path: &str, response: reqwest::Response) -> Result<R, Error>
+    where
+        R: DeserializeOwned,
+    {
+        let response = self.check_status(response)?;
+
+        // Only the media type, without parameters like `charset`.
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(|content_type| content_type.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+
+        let body = response.bytes().await?;
+
+        serde_json::from_slice(&body).map_err(|err| {
+            self.invalid_responses
+                .get_or_create(&InvalidResponseLabels {
+                    endpoint: path.to_string(),
+                    content_type: content_type.clone(),
+                })
+                .inc();
+
+            let path = path.to_string();
+
+            if content_type.is_empty() || content_type.contains("json") {
+                Error::InvalidJson { path, err }
+            } else {
+                Error::NotJson { path, content_type }
+            }
+        })
+    }
+
+    /// Same as [`reqwest::Response::error_for_status`], but counts errors.
+    fn check_status(&self, response: reqwest::Response) -> Result<reqwest::Response, Error> {
+        let status = response.status();
+
+        if status.is_client_error() || status.is_server_error() {
+            self.http_errors
+                .get_or_create(&HttpErrorLabels {
+                    code: status.as_u16().to_string(),
+                })
+                .inc();
+        }
+
+        Ok(response.error_for_status()?)
+    }
+}
+
+/// Accepts only the certificate with the pinned fingerprint, regardless of
+/// its issuer, validity period or names, while still checking that the Envoy
+/// holds the key of that certificate.
+#[derive(Debug)]
+struct FingerprintVerifier {
+    fingerprint: Vec<u8>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl FingerprintVerifier {
+    fn new(fingerprint: Vec<u8>) -> Self {
+        Self {
+            fingerprint,
+            algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        }
+    }
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, end_entity);
+
+        if digest.as_ref() != self.fingerprint {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    Http(reqwest::Error),
+    /// Authentication hasn't succeeded yet, so there's no token to use.
+    NoToken,
+    /// Enlighten issued a token that the Envoy doesn't accept.
+    TokenRejected,
+    /// Enlighten issued a token with a different scope than required.
+    TokenScope(String),
+    /// The Envoy responded with something other than JSON, usually an HTML page.
+    NotJson {
+        path: String,
+        content_type: String,
+    },
+    /// The Envoy responded with JSON that doesn't match the expected structure.
+    InvalidJson {
+        path: String,
+        err: serde_json::Error,
+    },
+}
+
+impl Error {
+    pub(crate) fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::Http(err) => err.status(),
+            Error::NoToken
+            | Error::TokenRejected
+            | Error::TokenScope(_)
+            | Error::NotJson { .. }
+            | Error::InvalidJson { .. } => None,
+        }
+    }
+
+    /// Kind of the problem shown by `check`, telling apart endpoints missing
+    /// from the firmware and credentials the Envoy doesn't accept.
+    pub(crate) fn problem(&self) -> &'static str {
+        match self {
+            Error::NoToken | Error::TokenRejected | Error::TokenScope(_) => "auth",
+            Error::NotJson { .. } => "unsupported",
+            Error::Http(_) => match self.status() {
+                Some(StatusCode::NOT_FOUND) => "unsupported",
+                Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => "auth",
+                _ => "error",
+            },
+            Error::InvalidJson { .. } => "error",
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Http(err)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Http(err) if err.status() == Some(StatusCode::UNAUTHORIZED) => write!(
+                f,
+                "{err}, the envoy didn't accept the credentials, \
+                 check that they are for this envoy and haven't expired"
+            ),
+            Error::Http(err) => err.fmt(f),
+            Error::NoToken => write!(f, "no auth token available yet"),
+            Error::TokenRejected => write!(
+                f,
+                "token was rejected by the envoy, check that the serial number \
+                 belongs to it and the username has access to it"
+            ),
+            Error::TokenScope(user) => write!(
+                f,
+                "installer token is required, but got a token for {user:?}, \
+                 log in with an installer account"
+            ),
+            Error::NotJson { path, content_type } => write!(
+                f,
+                "expected json from {path}, but got {content_type:?}, which usually \
+                 means that the envoy didn't accept the token or the firmware doesn't \
+                 support the endpoint"
+            ),
+            Error::InvalidJson { path, err } => {
+                write!(f, "unexpected json structure from {path}: {err}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// How the Envoy certificate is verified.
+pub(crate) enum EnvoyTls {
+    Any,
+    Certificate(reqwest::Certificate),
+    Fingerprint(Vec<u8>),
+}
+
+pub(crate) fn envoy_tls(envoy: &EnvoyArgs) -> Result<EnvoyTls, String> {
+    if let Some(path) = &envoy.envoy_ca_file {
+        return read_certificate(path).map(EnvoyTls::Certificate);
+    }
+
+    match &envoy.envoy_tls_fingerprint {
+        Some(fingerprint) => tls_fingerprint(fingerprint).map(EnvoyTls::Fingerprint),
+        None => Ok(EnvoyTls::Any),
+    }
+}
+
+/// Parses a SHA-256 fingerprint in hex, with or without colons between bytes.
+fn tls_fingerprint(fingerprint: &str) -> Result<Vec<u8>, String> {
+    let hex = fingerprint.replace(':', "");
+
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect::<Option<Vec<_>>>();
+
+    match bytes {
+        Some(bytes) if bytes.len() == ring::digest::SHA256_OUTPUT_LEN => Ok(bytes),
+        _ => Err(format!("invalid SHA-256 fingerprint {fingerprint:?}")),
+    }
+}
+
+fn read_certificate(path: &Path) -> Result<reqwest::Certificate, String> {
+    fs::read(path)
+        .ok()
+        .and_then(|pem| reqwest::Certificate::from_pem(&pem).ok())
+        .ok_or_else(|| format!("error reading certificate from {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprints() {
+        let fingerprint = "9F:86:D0:81:88:4C:7D:65:9A:2F:EA:A0:C5:5A:D0:15:A3:BF:4F:1B:2B:0B:82:2C:D1:5D:6C:15:B0:F0:0A:08";
+        let expected = ring::digest::digest(&ring::digest::SHA256, b"test");
+
+        assert_eq!(tls_fingerprint(fingerprint).unwrap(), expected.as_ref());
+        assert_eq!(
+            tls_fingerprint(&fingerprint.replace(':', "").to_lowercase()).unwrap(),
+            expected.as_ref()
+        );
+
+        assert!(tls_fingerprint(&fingerprint[3..]).is_err());
+        assert!(tls_fingerprint(&fingerprint[..fingerprint.len() - 1]).is_err());
+        assert!(tls_fingerprint(&fingerprint.replace('F', "G")).is_err());
+        assert!(tls_fingerprint("").is_err());
+    }
+}
@@ -0,0 +1,3852 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures::future::join_all;
+use prometheus_client::{
+    encoding::{EncodeLabel, EncodeLabelSet, LabelSetEncoder},
+    metrics::{counter::Counter, gauge::Gauge},
+};
+use reqwest::StatusCode;
+use serde_derive::Deserialize;
+use tokio::{
+    spawn,
+    sync::Mutex,
+    task::JoinHandle,
+    time::{timeout_at, MissedTickBehavior},
+};
+
+use crate::{
+    client::{Client, Error},
+    config::{DumpArgs, COLLECTORS},
+    metrics::{EnvoyRegistry, Family},
+    unix_time,
+};
+
+/// Meter reports list individual lines in order, without naming them.
+const PHASES: [&str; 3] = ["ph-a", "ph-b", "ph-c"];
+
+/// Device id of the Envoy itself, which is the same on every gateway.
+pub(crate) const ENVOY_EID: u64 = 603980032;
+
+/// Options shared by collectors of all Envoys.
+pub(crate) struct CollectorOptions {
+    pub(crate) collectors: HashSet<&'static str>,
+    pub(crate) inverter_stale_after: Duration,
+    pub(crate) max_inverters: usize,
+    pub(crate) inverter_names: Arc<HashMap<String, String>>,
+    pub(crate) fail_on_error: bool,
+    /// Prefix of metric names, to find metrics in the encoded output.
+    pub(crate) prefix: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct AppState {
+    pub(crate) client: Client,
+    pub(crate) registry: Arc<EnvoyRegistry>,
+    /// Labels of all metrics, which are set on the registry.
+    pub(crate) labels: Vec<(String, String)>,
+    pub(crate) prefix: String,
+    /// Names of collectors that are enabled.
+    collectors: Arc<HashSet<&'static str>>,
+    fail_on_error: bool,
+    breaker: CircuitBreaker,
+    /// When inverters were last listed by the Envoy, to remove replaced ones.
+    inverter_last_seen: Arc<std::sync::Mutex<HashMap<String, Instant>>>,
+    /// Inverters removed as stale, left out of inventory metrics until they
+    /// report production again, as the Envoy keeps replaced ones in its inventory.
+    stale_inverters: Arc<std::sync::Mutex<HashSet<String>>>,
+    inverter_stale_after: Duration,
+    /// Series of inverters beyond this number are dropped, so that a garbage
+    /// response can't create an unbounded number of series.
+    max_inverters: usize,
+    /// Names of inverters by serial number, for the `name` label.
+    inverter_names: Arc<HashMap<String, String>>,
+    last_scrape: Arc<Mutex<Option<Scrape>>>,
+    /// When the Envoy last reported production, for `/readyz`.
+    last_success: Arc<std::sync::Mutex<Option<Instant>>>,
+    /// Kind and message of the last error of every failing collector, for `check`.
+    last_errors: Arc<std::sync::Mutex<HashMap<&'static str, (&'static str, String)>>>,
+    /// Collectors still running after the deadline of the scrape are cancelled.
+    deadline: Option<Instant>,
+    pub(crate) up: Gauge,
+    breaker_open: Gauge,
+    collector_duration_seconds: Family<CollectorLabels, Gauge<f64, AtomicU64>>,
+    collector_scrapes: Family<CollectorLabels, Counter>,
+    collector_errors: Family<CollectorLabels, Counter>,
+    collector_success: Family<CollectorLabels, Gauge>,
+    counter_regressions: Family<CounterLabels, Counter>,
+    dropped_inverters: Family<CollectorLabels, Counter>,
+    collector_last_success_timestamp_seconds: Family<CollectorLabels, Gauge<f64, AtomicU64>>,
+    token_expiry_timestamp_seconds: Gauge,
+    pub(crate) info: Family<InfoLabels, Gauge>,
+    production_watts: Gauge<f64, AtomicU64>,
+    production_phase_watts: Family<PhaseLabels, Gauge<f64, AtomicU64>>,
+    inverter_production_watts: Family<InverterLabels, Gauge<f64, AtomicU64>>,
+    inverter_max_production_watts: Family<InverterLabels, Gauge<f64, AtomicU64>>,
+    inverter_last_report_timestamp_seconds: Family<InverterLabels, Gauge>,
+    inverter_dc_voltage_volts: Family<InverterLabels, Gauge<f64, AtomicU64>>,
+    inverter_dc_current_amperes: Family<InverterLabels, Gauge<f64, AtomicU64>>,
+    inverter_temperature_celsius: Family<InverterLabels, Gauge<f64, AtomicU64>>,
+    inverter_lifetime_watt_hours: Family<InverterLabels, Counter<f64, AtomicU64>>,
+    inverter_info: Family<InverterInfoLabels, Gauge>,
+    lifetime_watt_hours: Counter<f64, AtomicU64>,
+    consumption_watts: Family<MeterLabels, Gauge<f64, AtomicU64>>,
+    consumption_today_watt_hours: Family<MeterLabels, Gauge<f64, AtomicU64>>,
+    consumption_seven_days_watt_hours: Family<MeterLabels, Gauge<f64, AtomicU64>>,
+    consumption_lifetime_watt_hours: Family<MeterLabels, Counter<f64, AtomicU64>>,
+    meter_voltage_volts: Family<MeterLabels, Gauge<f64, AtomicU64>>,
+    meter_current_amperes: Family<MeterLabels, Gauge<f64, AtomicU64>>,
+    meter_frequency_hertz: Family<MeterLabels, Gauge<f64, AtomicU64>>,
+    meter_power_factor: Family<MeterLabels, Gauge<f64, AtomicU64>>,
+    reactive_power_var: Family<MeterLabels, Gauge<f64, AtomicU64>>,
+    apparent_power_va: Family<MeterLabels, Gauge<f64, AtomicU64>>,
+    battery_charge_percent: Family<BatteryLabels, Gauge<f64, AtomicU64>>,
+    battery_capacity_watt_hours: Family<BatteryLabels, Gauge<f64, AtomicU64>>,
+    battery_available_watt_hours: Family<BatteryLabels, Gauge<f64, AtomicU64>>,
+    battery_temperature_celsius: Family<BatteryLabels, Gauge<f64, AtomicU64>>,
+    battery_real_power_watts: Family<BatteryLabels, Gauge<f64, AtomicU64>>,
+    battery_apparent_power_va: Family<BatteryLabels, Gauge<f64, AtomicU64>>,
+    battery_charge_watts: Gauge<f64, AtomicU64>,
+    battery_discharge_watts: Gauge<f64, AtomicU64>,
+    battery_aggregate_charge_percent: Gauge<f64, AtomicU64>,
+    battery_aggregate_capacity_watt_hours: Gauge<f64, AtomicU64>,
+    battery_backup_watt_hours: Gauge<f64, AtomicU64>,
+    battery_reserve_percent: Gauge<f64, AtomicU64>,
+    battery_charge_from_grid_enabled: Gauge,
+    battery_charge_from_grid_window_info: Family<ChargeWindowLabels, Gauge>,
+    dry_contact_closed: Family<DryContactLabels, Gauge>,
+    dry_contact_info: Family<DryContactInfoLabels, Gauge>,
+    generator_state_info: Family<GeneratorStateLabels, Gauge>,
+    generator_start_charge_percent: Gauge<f64, AtomicU64>,
+    generator_stop_charge_percent: Gauge<f64, AtomicU64>,
+    generator_runtime_seconds: Gauge<f64, AtomicU64>,
+    grid_profile_info: Family<GridProfileLabels, Gauge>,
+    export_limit_enabled: Gauge,
+    export_limit_watts: Gauge<f64, AtomicU64>,
+    production_forced_off: Gauge,
+    storm_guard_enabled: Gauge,
+    storm_alert_active: Gauge,
+    enpower_relay_closed: Family<EnpowerRelayLabels, Gauge>,
+    grid_connected: Family<EnpowerLabels, Gauge>,
+    grid_state_info: Family<GridStateLabels, Gauge>,
+    grid_import_watt_hours: Counter<f64, AtomicU64>,
+    grid_export_watt_hours: Counter<f64, AtomicU64>,
+    production_today_watt_hours: Gauge<f64, AtomicU64>,
+    production_seven_days_watt_hours: Gauge<f64, AtomicU64>,
+    device_producing: Family<DeviceLabels, Gauge>,
+    device_communicating: Family<DeviceLabels, Gauge>,
+    device_provisioned: Family<DeviceLabels, Gauge>,
+    device_status_info: Family<DeviceStatusLabels, Gauge>,
+    relay_closed: Family<RelayLabels, Gauge>,
+    relay_forced: Family<RelayLabels, Gauge>,
+    relay_line_connected: Family<RelayLineLabels, Gauge>,
+    inverters_total: Gauge,
+    inverters_producing: Gauge,
+    inverters_communicating: Gauge,
+    database_size_bytes: Gauge<f64, AtomicU64>,
+    database_full_percent: Gauge<f64, AtomicU64>,
+    last_enlighten_report_timestamp_seconds: Gauge,
+    time_seconds: Gauge<f64, AtomicU64>,
+    clock_offset_seconds: Gauge<f64, AtomicU64>,
+    ntp_enabled: Gauge,
+    internet_connected: Gauge,
+    network_interface_info: Family<NetworkInterfaceInfoLabels, Gauge>,
+    network_interface_carrier: Family<NetworkInterfaceLabels, Gauge>,
+    network_interface_signal_strength: Family<NetworkInterfaceLabels, Gauge>,
+    network_interface_signal_strength_max: Family<NetworkInterfaceLabels, Gauge>,
+    cellular_signal_strength: Family<ModemLabels, Gauge>,
+    cellular_connected: Family<ModemLabels, Gauge>,
+    events: Family<EventLabels, Counter>,
+    last_event_info: Family<LastEventLabels, Gauge>,
+    /// Unset until the first poll, so that events logged before the exporter
+    /// started are not counted.
+    last_event_id: Arc<std::sync::Mutex<Option<u64>>>,
+    tariff_info: Family<TariffLabels, Gauge>,
+    tariff_rate: Family<TariffRateLabels, Gauge<f64, AtomicU64>>,
+    tariff_period_rate: Family<TariffPeriodLabels, Gauge<f64, AtomicU64>>,
+    meter_info: Family<MeterInfoLabels, Gauge>,
+    meter_reading_active_power_watts: Family<MeterReadingLabels, Gauge<f64, AtomicU64>>,
+    meter_reading_current_amperes: Family<MeterReadingLabels, Gauge<f64, AtomicU64>>,
+    meter_reading_voltage_volts: Family<MeterReadingLabels, Gauge<f64, AtomicU64>>,
+    meter_reading_frequency_hertz: Family<MeterReadingLabels, Gauge<f64, AtomicU64>>,
+    livedata_power_watts: Family<LivedataLabels, Gauge<f64, AtomicU64>>,
+    acb_power_watts: Gauge<f64, AtomicU64>,
+    acb_stored_watt_hours: Gauge<f64, AtomicU64>,
+    acb_state_info: Family<AcbStateLabels, Gauge>,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CollectorLabels {
+    collector: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct HttpErrorLabels {
+    pub(crate) code: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct InvalidResponseLabels {
+    pub(crate) endpoint: String,
+    pub(crate) content_type: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CounterLabels {
+    metric: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct EndpointLabels {
+    pub(crate) endpoint: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct BuildInfoLabels {
+    pub(crate) version: String,
+    pub(crate) revision: String,
+    pub(crate) rustc_version: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct InfoLabels {
+    pub(crate) serial_num: String,
+    pub(crate) part_num: String,
+    pub(crate) software_version: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PhaseLabels {
+    phase: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct InverterLabels {
+    serial_num: String,
+    /// Left out instead of being empty for inverters without a name.
+    name: Option<String>,
+}
+
+impl EncodeLabelSet for InverterLabels {
+    fn encode(&self, mut encoder: LabelSetEncoder) -> Result<(), std::fmt::Error> {
+        ("serial_num", self.serial_num.as_str()).encode(encoder.encode_label())?;
+
+        if let Some(name) = &self.name {
+            ("name", name.as_str()).encode(encoder.encode_label())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct InverterInfoLabels {
+    serial_num: String,
+    part_num: String,
+    img_load_date: String,
+    firmware: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct MeterLabels {
+    report_type: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct MeterInfoLabels {
+    eid: String,
+    state: String,
+    measurement_type: String,
+    metering_status: String,
+    phase_mode: String,
+    phase_count: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct MeterReadingLabels {
+    eid: String,
+    measurement_type: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct LivedataLabels {
+    source: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct AcbStateLabels {
+    state: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct BatteryLabels {
+    serial_num: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ChargeWindowLabels {
+    season: String,
+    days: String,
+    start: String,
+    duration: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DryContactLabels {
+    id: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DryContactInfoLabels {
+    id: String,
+    load_name: String,
+    mode: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct GeneratorStateLabels {
+    state: String,
+    mode: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub(crate) struct GridProfileLabels {
+    pub(crate) name: String,
+    pub(crate) version: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct EnpowerRelayLabels {
+    relay: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct EnpowerLabels {
+    serial_num: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DeviceLabels {
+    serial_num: String,
+    device_type: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DeviceStatusLabels {
+    serial_num: String,
+    device_type: String,
+    device_status: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RelayLabels {
+    serial_num: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RelayLineLabels {
+    serial_num: String,
+    line: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct NetworkInterfaceLabels {
+    interface: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct NetworkInterfaceInfoLabels {
+    interface: String,
+    interface_type: String,
+    ip: String,
+    dhcp: String,
+    primary: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ModemLabels {
+    imei: String,
+}
+
+/// Event descriptions are free text and localized by the firmware, so only
+/// the last event is labelled with one.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct EventLabels {
+    device_type: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct LastEventLabels {
+    event: String,
+    device_type: String,
+    serial_num: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TariffLabels {
+    currency: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TariffRateLabels {
+    direction: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TariffPeriodLabels {
+    direction: String,
+    season: String,
+    days: String,
+    period: String,
+    start: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct GridStateLabels {
+    serial_num: String,
+    grid_state: String,
+}
+
+impl AppState {
+    pub(crate) fn new(
+        client: Client,
+        labels: Vec<(String, String)>,
+        options: &CollectorOptions,
+        breaker: CircuitBreaker,
+    ) -> Self {
+        let mut registry = EnvoyRegistry::new(labels.clone());
+
+        let up = Gauge::default();
+
+        registry.register(
+            "up",
+            "Whether the Envoy was reachable and reported production",
+            up.clone(),
+        );
+
+        let breaker_open = Gauge::default();
+
+        registry.register(
+            "breaker_open",
+            "Whether requests to the Envoy are paused after failed scrapes",
+            breaker_open.clone(),
+        );
+
+        let collector_duration_seconds =
+            Family::<CollectorLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "collector_duration_seconds",
+            "Time spent by the collector on the last scrape",
+            collector_duration_seconds.clone(),
+        );
+
+        let collector_scrapes = Family::<CollectorLabels, Counter>::default();
+
+        registry.register(
+            "collector_scrapes",
+            "Number of times the collector ran",
+            collector_scrapes.clone(),
+        );
+
+        let collector_errors = Family::<CollectorLabels, Counter>::default();
+
+        registry.register(
+            "collector_errors",
+            "Number of times the collector failed",
+            collector_errors.clone(),
+        );
+
+        let collector_success = Family::<CollectorLabels, Gauge>::default();
+
+        registry.register(
+            "collector_success",
+            "Whether the last run of the collector succeeded",
+            collector_success.clone(),
+        );
+
+        let counter_regressions = Family::<CounterLabels, Counter>::default();
+
+        registry.register(
+            "counter_regressions",
+            "Number of times the Envoy reported a lower value for a counter",
+            counter_regressions.clone(),
+        );
+
+        let dropped_inverters = Family::<CollectorLabels, Counter>::default();
+
+        registry.register(
+            "dropped_inverters",
+            "Number of times inverters beyond the maximum were not exported",
+            dropped_inverters.clone(),
+        );
+
+        let collector_last_success_timestamp_seconds =
+            Family::<CollectorLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "collector_last_success_timestamp_seconds",
+            "Timestamp of the last successful run of the collector",
+            collector_last_success_timestamp_seconds.clone(),
+        );
+
+        let token_expiry_timestamp_seconds = Gauge::default();
+
+        registry.register(
+            "token_expiry_timestamp_seconds",
+            "Expiration time of the Envoy auth token",
+            token_expiry_timestamp_seconds.clone(),
+        );
+
+        registry.register(
+            "auth_refreshes",
+            "Number of times a new token was obtained from Enlighten",
+            client.auth_refreshes.clone(),
+        );
+
+        registry.register(
+            "auth_failures",
+            "Number of failed attempts to obtain a token from Enlighten",
+            client.auth_failures.clone(),
+        );
+
+        registry.register(
+            "http_errors",
+            "Number of error responses from the Envoy",
+            client.http_errors.clone(),
+        );
+
+        registry.register(
+            "invalid_responses",
+            "Number of successful responses from the Envoy that couldn't be parsed",
+            client.invalid_responses.clone(),
+        );
+
+        registry.register(
+            "request_duration_seconds",
+            "Round trip time of requests to the Envoy",
+            client.request_duration_seconds.clone(),
+        );
+
+        let info = Family::<InfoLabels, Gauge>::default();
+
+        registry.register("info", "Information about the Envoy", info.clone());
+
+        let production_watts = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "production_watts",
+            "Currently produced watts",
+            production_watts.clone(),
+        );
+
+        let production_phase_watts = Family::<PhaseLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "production_phase_watts",
+            "Currently produced watts per phase",
+            production_phase_watts.clone(),
+        );
+
+        let inverter_production_watts = Family::<InverterLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "inverter_production_watts",
+            "Last known production for inverters",
+            inverter_production_watts.clone(),
+        );
+
+        let inverter_max_production_watts =
+            Family::<InverterLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "inverter_max_production_watts",
+            "Maximum reported production for inverters",
+            inverter_max_production_watts.clone(),
+        );
+
+        let inverter_last_report_timestamp_seconds = Family::<InverterLabels, Gauge>::default();
+
+        registry.register(
+            "inverter_last_report_timestamp_seconds",
+            "Timestamp of the last report from inverters",
+            inverter_last_report_timestamp_seconds.clone(),
+        );
+
+        let inverter_dc_voltage_volts = Family::<InverterLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "inverter_dc_voltage_volts",
+            "DC voltage on the input of inverters",
+            inverter_dc_voltage_volts.clone(),
+        );
+
+        let inverter_dc_current_amperes =
+            Family::<InverterLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "inverter_dc_current_amperes",
+            "DC current on the input of inverters",
+            inverter_dc_current_amperes.clone(),
+        );
+
+        let inverter_temperature_celsius =
+            Family::<InverterLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "inverter_temperature_celsius",
+            "Temperature of inverters",
+            inverter_temperature_celsius.clone(),
+        );
+
+        let inverter_lifetime_watt_hours =
+            Family::<InverterLabels, Counter<f64, AtomicU64>>::default();
+
+        registry.register(
+            "inverter_lifetime_watt_hours",
+            "Total amount of watt hours produced by inverters",
+            inverter_lifetime_watt_hours.clone(),
+        );
+
+        let inverter_info = Family::<InverterInfoLabels, Gauge>::default();
+
+        registry.register(
+            "inverter_info",
+            "Hardware and firmware information for inverters",
+            inverter_info.clone(),
+        );
+
+        let lifetime_watt_hours = Counter::<f64, AtomicU64>::default();
+
+        registry.register(
+            "lifetime_watt_hours",
+            "Total amount of watt hours produced by the system",
+            lifetime_watt_hours.clone(),
+        );
+
+        let consumption_watts = Family::<MeterLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "consumption_watts",
+            "Currently consumed watts",
+            consumption_watts.clone(),
+        );
+
+        let consumption_today_watt_hours = Family::<MeterLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "consumption_today_watt_hours",
+            "Amount of watt hours consumed today",
+            consumption_today_watt_hours.clone(),
+        );
+
+        let consumption_seven_days_watt_hours =
+            Family::<MeterLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "consumption_seven_days_watt_hours",
+            "Amount of watt hours consumed over the last seven days",
+            consumption_seven_days_watt_hours.clone(),
+        );
+
+        let consumption_lifetime_watt_hours =
+            Family::<MeterLabels, Counter<f64, AtomicU64>>::default();
+
+        registry.register(
+            "consumption_lifetime_watt_hours",
+            "Total amount of watt hours consumed",
+            consumption_lifetime_watt_hours.clone(),
+        );
+
+        let meter_voltage_volts = Family::<MeterLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "meter_voltage_volts",
+            "RMS voltage reported by the meter",
+            meter_voltage_volts.clone(),
+        );
+
+        let meter_current_amperes = Family::<MeterLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "meter_current_amperes",
+            "RMS current reported by the meter",
+            meter_current_amperes.clone(),
+        );
+
+        let meter_frequency_hertz = Family::<MeterLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "meter_frequency_hertz",
+            "Grid frequency reported by the meter",
+            meter_frequency_hertz.clone(),
+        );
+
+        let meter_power_factor = Family::<MeterLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "meter_power_factor",
+            "Power factor reported by the meter",
+            meter_power_factor.clone(),
+        );
+
+        let reactive_power_var = Family::<MeterLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "reactive_power_var",
+            "Reactive power reported by the meter",
+            reactive_power_var.clone(),
+        );
+
+        let apparent_power_va = Family::<MeterLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "apparent_power_va",
+            "Apparent power reported by the meter",
+            apparent_power_va.clone(),
+        );
+
+        let battery_charge_percent = Family::<BatteryLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "battery_charge_percent",
+            "State of charge of the battery",
+            battery_charge_percent.clone(),
+        );
+
+        let battery_capacity_watt_hours = Family::<BatteryLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "battery_capacity_watt_hours",
+            "Maximum capacity of the battery",
+            battery_capacity_watt_hours.clone(),
+        );
+
+        let battery_available_watt_hours =
+            Family::<BatteryLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "battery_available_watt_hours",
+            "Energy currently stored in the battery",
+            battery_available_watt_hours.clone(),
+        );
+
+        let battery_temperature_celsius = Family::<BatteryLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "battery_temperature_celsius",
+            "Temperature of the battery",
+            battery_temperature_celsius.clone(),
+        );
+
+        let battery_real_power_watts = Family::<BatteryLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "battery_real_power_watts",
+            "Real power of the battery, positive when charging",
+            battery_real_power_watts.clone(),
+        );
+
+        let battery_apparent_power_va = Family::<BatteryLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "battery_apparent_power_va",
+            "Apparent power of the battery",
+            battery_apparent_power_va.clone(),
+        );
+
+        let battery_charge_watts = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "battery_charge_watts",
+            "Power used to charge all batteries combined",
+            battery_charge_watts.clone(),
+        );
+
+        let battery_discharge_watts = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "battery_discharge_watts",
+            "Power provided by discharging all batteries combined",
+            battery_discharge_watts.clone(),
+        );
+
+        let battery_aggregate_charge_percent = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "battery_aggregate_charge_percent",
+            "State of charge of all batteries combined",
+            battery_aggregate_charge_percent.clone(),
+        );
+
+        let battery_aggregate_capacity_watt_hours = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "battery_aggregate_capacity_watt_hours",
+            "Maximum capacity of all batteries combined",
+            battery_aggregate_capacity_watt_hours.clone(),
+        );
+
+        let battery_backup_watt_hours = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "battery_backup_watt_hours",
+            "Energy in batteries available for backup",
+            battery_backup_watt_hours.clone(),
+        );
+
+        let battery_reserve_percent = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "battery_reserve_percent",
+            "State of charge reserved for backup",
+            battery_reserve_percent.clone(),
+        );
+
+        let battery_charge_from_grid_enabled = Gauge::default();
+
+        registry.register(
+            "battery_charge_from_grid_enabled",
+            "Whether batteries are allowed to charge from the grid",
+            battery_charge_from_grid_enabled.clone(),
+        );
+
+        let battery_charge_from_grid_window_info = Family::<ChargeWindowLabels, Gauge>::default();
+
+        registry.register(
+            "battery_charge_from_grid_window_info",
+            "Schedule for charging batteries from the grid",
+            battery_charge_from_grid_window_info.clone(),
+        );
+
+        let dry_contact_closed = Family::<DryContactLabels, Gauge>::default();
+
+        registry.register(
+            "dry_contact_closed",
+            "Whether the dry contact is closed",
+            dry_contact_closed.clone(),
+        );
+
+        let dry_contact_info = Family::<DryContactInfoLabels, Gauge>::default();
+
+        registry.register(
+            "dry_contact_info",
+            "Configuration of the dry contact",
+            dry_contact_info.clone(),
+        );
+
+        let generator_state_info = Family::<GeneratorStateLabels, Gauge>::default();
+
+        registry.register(
+            "generator_state_info",
+            "State of the backup generator",
+            generator_state_info.clone(),
+        );
+
+        let generator_start_charge_percent = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "generator_start_charge_percent",
+            "Battery state of charge at which the generator starts",
+            generator_start_charge_percent.clone(),
+        );
+
+        let generator_stop_charge_percent = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "generator_stop_charge_percent",
+            "Battery state of charge at which the generator stops",
+            generator_stop_charge_percent.clone(),
+        );
+
+        let generator_runtime_seconds = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "generator_runtime_seconds",
+            "Runtime of the backup generator",
+            generator_runtime_seconds.clone(),
+        );
+
+        let grid_profile_info = Family::<GridProfileLabels, Gauge>::default();
+
+        registry.register(
+            "grid_profile_info",
+            "Grid profile selected on the Envoy",
+            grid_profile_info.clone(),
+        );
+
+        let export_limit_enabled = Gauge::default();
+
+        registry.register(
+            "export_limit_enabled",
+            "Whether power export limiting is enabled",
+            export_limit_enabled.clone(),
+        );
+
+        let export_limit_watts = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "export_limit_watts",
+            "Configured power export limit",
+            export_limit_watts.clone(),
+        );
+
+        let production_forced_off = Gauge::default();
+
+        registry.register(
+            "production_forced_off",
+            "Whether production has been administratively turned off",
+            production_forced_off.clone(),
+        );
+
+        let storm_guard_enabled = Gauge::default();
+
+        registry.register(
+            "storm_guard_enabled",
+            "Whether Storm Guard is enabled",
+            storm_guard_enabled.clone(),
+        );
+
+        let storm_alert_active = Gauge::default();
+
+        registry.register(
+            "storm_alert_active",
+            "Whether a storm alert is active and batteries are held at full reserve",
+            storm_alert_active.clone(),
+        );
+
+        let enpower_relay_closed = Family::<EnpowerRelayLabels, Gauge>::default();
+
+        registry.register(
+            "enpower_relay_closed",
+            "Whether the relay in the IQ System Controller is closed",
+            enpower_relay_closed.clone(),
+        );
+
+        let grid_connected = Family::<EnpowerLabels, Gauge>::default();
+
+        registry.register(
+            "grid_connected",
+            "Whether the IQ System Controller is connected to the grid",
+            grid_connected.clone(),
+        );
+
+        let grid_state_info = Family::<GridStateLabels, Gauge>::default();
+
+        registry.register(
+            "grid_state_info",
+            "Grid mode reported by the IQ System Controller",
+            grid_state_info.clone(),
+        );
+
+        let grid_import_watt_hours = Counter::<f64, AtomicU64>::default();
+
+        registry.register(
+            "grid_import_watt_hours",
+            "Total amount of watt hours imported from the grid",
+            grid_import_watt_hours.clone(),
+        );
+
+        let grid_export_watt_hours = Counter::<f64, AtomicU64>::default();
+
+        registry.register(
+            "grid_export_watt_hours",
+            "Total amount of watt hours exported to the grid",
+            grid_export_watt_hours.clone(),
+        );
+
+        let production_today_watt_hours = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "production_today_watt_hours",
+            "Amount of watt hours produced today",
+            production_today_watt_hours.clone(),
+        );
+
+        let production_seven_days_watt_hours = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "production_seven_days_watt_hours",
+            "Amount of watt hours produced over the last seven days",
+            production_seven_days_watt_hours.clone(),
+        );
+
+        let device_producing = Family::<DeviceLabels, Gauge>::default();
+
+        registry.register(
+            "device_producing",
+            "Whether the device is producing power",
+            device_producing.clone(),
+        );
+
+        let device_communicating = Family::<DeviceLabels, Gauge>::default();
+
+        registry.register(
+            "device_communicating",
+            "Whether the device is communicating with the Envoy",
+            device_communicating.clone(),
+        );
+
+        let device_provisioned = Family::<DeviceLabels, Gauge>::default();
+
+        registry.register(
+            "device_provisioned",
+            "Whether the device is provisioned on the Envoy",
+            device_provisioned.clone(),
+        );
+
+        let device_status_info = Family::<DeviceStatusLabels, Gauge>::default();
+
+        registry.register(
+            "device_status_info",
+            "Status codes reported for the device",
+            device_status_info.clone(),
+        );
+
+        let relay_closed = Family::<RelayLabels, Gauge>::default();
+
+        registry.register(
+            "relay_closed",
+            "Whether the IQ Relay is closed",
+            relay_closed.clone(),
+        );
+
+        let relay_forced = Family::<RelayLabels, Gauge>::default();
+
+        registry.register(
+            "relay_forced",
+            "Whether the state of the IQ Relay is forced",
+            relay_forced.clone(),
+        );
+
+        let relay_line_connected = Family::<RelayLineLabels, Gauge>::default();
+
+        registry.register(
+            "relay_line_connected",
+            "Whether the line of the IQ Relay is connected",
+            relay_line_connected.clone(),
+        );
+
+        let inverters_total = Gauge::default();
+
+        registry.register(
+            "inverters_total",
+            "Number of inverters in the inventory",
+            inverters_total.clone(),
+        );
+
+        let inverters_producing = Gauge::default();
+
+        registry.register(
+            "inverters_producing",
+            "Number of inverters that are producing power",
+            inverters_producing.clone(),
+        );
+
+        let inverters_communicating = Gauge::default();
+
+        registry.register(
+            "inverters_communicating",
+            "Number of inverters that are communicating with the Envoy",
+            inverters_communicating.clone(),
+        );
+
+        let database_size_bytes = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "database_size_bytes",
+            "Size of the Envoy database",
+            database_size_bytes.clone(),
+        );
+
+        let database_full_percent = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "database_full_percent",
+            "How full the Envoy database is",
+            database_full_percent.clone(),
+        );
+
+        let last_enlighten_report_timestamp_seconds = Gauge::default();
+
+        registry.register(
+            "last_enlighten_report_timestamp_seconds",
+            "Timestamp of the last report to Enlighten",
+            last_enlighten_report_timestamp_seconds.clone(),
+        );
+
+        let time_seconds = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "time_seconds",
+            "Current time on the Envoy",
+            time_seconds.clone(),
+        );
+
+        let clock_offset_seconds = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "clock_offset_seconds",
+            "Difference between time on the Envoy and the exporter",
+            clock_offset_seconds.clone(),
+        );
+
+        let ntp_enabled = Gauge::default();
+
+        registry.register(
+            "ntp_enabled",
+            "Whether the Envoy synchronizes its clock over NTP",
+            ntp_enabled.clone(),
+        );
+
+        let internet_connected = Gauge::default();
+
+        registry.register(
+            "internet_connected",
+            "Whether the Envoy can communicate with Enlighten",
+            internet_connected.clone(),
+        );
+
+        let network_interface_info = Family::<NetworkInterfaceInfoLabels, Gauge>::default();
+
+        registry.register(
+            "network_interface_info",
+            "Information about network interfaces of the Envoy",
+            network_interface_info.clone(),
+        );
+
+        let network_interface_carrier = Family::<NetworkInterfaceLabels, Gauge>::default();
+
+        registry.register(
+            "network_interface_carrier",
+            "Whether the network interface has a link",
+            network_interface_carrier.clone(),
+        );
+
+        let network_interface_signal_strength = Family::<NetworkInterfaceLabels, Gauge>::default();
+
+        registry.register(
+            "network_interface_signal_strength",
+            "Signal strength of the wireless network interface",
+            network_interface_signal_strength.clone(),
+        );
+
+        let network_interface_signal_strength_max =
+            Family::<NetworkInterfaceLabels, Gauge>::default();
+
+        registry.register(
+            "network_interface_signal_strength_max",
+            "Maximum signal strength of the wireless network interface",
+            network_interface_signal_strength_max.clone(),
+        );
+
+        let cellular_signal_strength = Family::<ModemLabels, Gauge>::default();
+
+        registry.register(
+            "cellular_signal_strength",
+            "Signal strength of the Mobile Connect modem",
+            cellular_signal_strength.clone(),
+        );
+
+        let cellular_connected = Family::<ModemLabels, Gauge>::default();
+
+        registry.register(
+            "cellular_connected",
+            "Whether the Mobile Connect modem is connected",
+            cellular_connected.clone(),
+        );
+
+        let events = Family::<EventLabels, Counter>::default();
+
+        registry.register(
+            "events",
+            "Number of events logged by the Envoy",
+            events.clone(),
+        );
+
+        let last_event_info = Family::<LastEventLabels, Gauge>::default();
+
+        registry.register(
+            "last_event_info",
+            "Most recent event logged by the Envoy",
+            last_event_info.clone(),
+        );
+
+        let last_event_id = Arc::default();
+
+        let tariff_info = Family::<TariffLabels, Gauge>::default();
+
+        registry.register(
+            "tariff_info",
+            "Tariff configured on the Envoy",
+            tariff_info.clone(),
+        );
+
+        let tariff_rate = Family::<TariffRateLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "tariff_rate",
+            "Flat rate per kWh configured on the Envoy",
+            tariff_rate.clone(),
+        );
+
+        let tariff_period_rate = Family::<TariffPeriodLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "tariff_period_rate",
+            "Time of use rate per kWh configured on the Envoy",
+            tariff_period_rate.clone(),
+        );
+
+        let meter_info = Family::<MeterInfoLabels, Gauge>::default();
+
+        registry.register(
+            "meter_info",
+            "Configuration of CT meters",
+            meter_info.clone(),
+        );
+
+        let meter_reading_active_power_watts =
+            Family::<MeterReadingLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "meter_reading_active_power_watts",
+            "Instantaneous active power measured by the meter",
+            meter_reading_active_power_watts.clone(),
+        );
+
+        let meter_reading_current_amperes =
+            Family::<MeterReadingLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "meter_reading_current_amperes",
+            "Instantaneous current measured by the meter",
+            meter_reading_current_amperes.clone(),
+        );
+
+        let meter_reading_voltage_volts =
+            Family::<MeterReadingLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "meter_reading_voltage_volts",
+            "Instantaneous voltage measured by the meter",
+            meter_reading_voltage_volts.clone(),
+        );
+
+        let meter_reading_frequency_hertz =
+            Family::<MeterReadingLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "meter_reading_frequency_hertz",
+            "Instantaneous frequency measured by the meter",
+            meter_reading_frequency_hertz.clone(),
+        );
+
+        let livedata_power_watts = Family::<LivedataLabels, Gauge<f64, AtomicU64>>::default();
+
+        registry.register(
+            "livedata_power_watts",
+            "Instantaneous power flow reported by livedata",
+            livedata_power_watts.clone(),
+        );
+
+        let acb_power_watts = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "acb_power_watts",
+            "Current power of AC Batteries",
+            acb_power_watts.clone(),
+        );
+
+        let acb_stored_watt_hours = Gauge::<f64, AtomicU64>::default();
+
+        registry.register(
+            "acb_stored_watt_hours",
+            "Energy currently stored in AC Batteries",
+            acb_stored_watt_hours.clone(),
+        );
+
+        let acb_state_info = Family::<AcbStateLabels, Gauge>::default();
+
+        registry.register(
+            "acb_state_info",
+            "State of AC Batteries",
+            acb_state_info.clone(),
+        );
+
+        let registry = Arc::new(registry);
+
+        Self {
+            client,
+            registry,
+            labels,
+            prefix: options.prefix.clone(),
+            collectors: Arc::new(options.collectors.clone()),
+            fail_on_error: options.fail_on_error,
+            breaker,
+            inverter_last_seen: Arc::default(),
+            stale_inverters: Arc::default(),
+            inverter_stale_after: options.inverter_stale_after,
+            max_inverters: options.max_inverters,
+            inverter_names: options.inverter_names.clone(),
+            last_scrape: Arc::default(),
+            last_success: Arc::default(),
+            last_errors: Arc::default(),
+            deadline: None,
+            up,
+            breaker_open,
+            collector_duration_seconds,
+            collector_scrapes,
+            collector_errors,
+            collector_success,
+            counter_regressions,
+            dropped_inverters,
+            collector_last_success_timestamp_seconds,
+            token_expiry_timestamp_seconds,
+            info,
+            production_watts,
+            production_phase_watts,
+            inverter_production_watts,
+            inverter_max_production_watts,
+            inverter_last_report_timestamp_seconds,
+            inverter_dc_voltage_volts,
+            inverter_dc_current_amperes,
+            inverter_temperature_celsius,
+            inverter_lifetime_watt_hours,
+            inverter_info,
+            lifetime_watt_hours,
+            consumption_watts,
+            consumption_today_watt_hours,
+            consumption_seven_days_watt_hours,
+            consumption_lifetime_watt_hours,
+            meter_voltage_volts,
+            meter_current_amperes,
+            meter_frequency_hertz,
+            meter_power_factor,
+            reactive_power_var,
+            apparent_power_va,
+            battery_charge_percent,
+            battery_capacity_watt_hours,
+            battery_available_watt_hours,
+            battery_temperature_celsius,
+            battery_real_power_watts,
+            battery_apparent_power_va,
+            battery_charge_watts,
+            battery_discharge_watts,
+            battery_aggregate_charge_percent,
+            battery_aggregate_capacity_watt_hours,
+            battery_backup_watt_hours,
+            battery_reserve_percent,
+            battery_charge_from_grid_enabled,
+            battery_charge_from_grid_window_info,
+            dry_contact_closed,
+            dry_contact_info,
+            generator_state_info,
+            generator_start_charge_percent,
+            generator_stop_charge_percent,
+            generator_runtime_seconds,
+            grid_profile_info,
+            export_limit_enabled,
+            export_limit_watts,
+            production_forced_off,
+            storm_guard_enabled,
+            storm_alert_active,
+            enpower_relay_closed,
+            grid_connected,
+            grid_state_info,
+            grid_import_watt_hours,
+            grid_export_watt_hours,
+            production_today_watt_hours,
+            production_seven_days_watt_hours,
+            device_producing,
+            device_communicating,
+            device_provisioned,
+            device_status_info,
+            relay_closed,
+            relay_forced,
+            relay_line_connected,
+            inverters_total,
+            inverters_producing,
+            inverters_communicating,
+            database_size_bytes,
+            database_full_percent,
+            last_enlighten_report_timestamp_seconds,
+            time_seconds,
+            clock_offset_seconds,
+            ntp_enabled,
+            internet_connected,
+            network_interface_info,
+            network_interface_carrier,
+            network_interface_signal_strength,
+            network_interface_signal_strength_max,
+            cellular_signal_strength,
+            cellular_connected,
+            events,
+            last_event_info,
+            last_event_id,
+            tariff_info,
+            tariff_rate,
+            tariff_period_rate,
+            meter_info,
+            meter_reading_active_power_watts,
+            meter_reading_current_amperes,
+            meter_reading_voltage_volts,
+            meter_reading_frequency_hertz,
+            livedata_power_watts,
+            acb_power_watts,
+            acb_stored_watt_hours,
+            acb_state_info,
+        }
+    }
+
+    /// Replaces the client and options on reload, keeping the registry
+    /// and metrics. Series of collectors that were turned off stay with
+    /// their last values until restart.
+    pub(crate) fn reconfigure(
+        &self,
+        client: Client,
+        options: &CollectorOptions,
+        breaker: CircuitBreaker,
+    ) -> Self {
+        // Series with old names would otherwise stay around until restart.
+        if options.inverter_names != self.inverter_names {
+            self.clear_inverters();
+        }
+
+        Self {
+            client: client.with_metrics_of(&self.client),
+            collectors: Arc::new(options.collectors.clone()),
+            fail_on_error: options.fail_on_error,
+            breaker,
+            inverter_stale_after: options.inverter_stale_after,
+            max_inverters: options.max_inverters,
+            inverter_names: options.inverter_names.clone(),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn succeeded_within(&self, max_age: Duration) -> bool {
+        self.last_success
+            .lock()
+            .expect("last success lock poisoned")
+            .is_some_and(|last| last.elapsed() <= max_age)
+    }
+
+    /// Removes series of inverters that weren't seen for a while,
+    /// which happens when they are replaced or de-provisioned.
+    fn remove_stale_inverters(&self, seen: impl Iterator<Item = String>) {
+        let now = Instant::now();
+
+        let mut stale_inverters = self
+            .stale_inverters
+            .lock()
+            .expect("stale inverters lock poisoned");
+
+        let stale = {
+            let mut last_seen = self
+                .inverter_last_seen
+                .lock()
+                .expect("inverter last seen lock poisoned");
+
+            for serial_num in seen {
+                stale_inverters.remove(&serial_num);
+
+                if last_seen.contains_key(&serial_num) || last_seen.len() < self.max_inverters {
+                    last_seen.insert(serial_num, now);
+                }
+            }
+
+            let stale = last_seen
+                .iter()
+                .filter(|(_, seen)| now.duration_since(**seen) > self.inverter_stale_after)
+                .map(|(serial_num, _)| serial_num.clone())
+                .collect::<Vec<_>>();
+
+            for serial_num in &stale {
+                last_seen.remove(serial_num);
+            }
+
+            stale
+        };
+
+        for serial_num in stale {
+            // Info series are rebuilt by the inventory collector, which skips stale inverters.
+            let device_labels = DeviceLabels {
+                serial_num: serial_num.clone(),
+                device_type: "PCU".to_string(),
+            };
+
+            for family in [
+                &self.device_producing,
+                &self.device_communicating,
+                &self.device_provisioned,
+            ] {
+                family.remove(&device_labels);
+            }
+
+            stale_inverters.insert(serial_num.clone());
+
+            let labels = InverterLabels {
+                name: self.inverter_names.get(&serial_num).cloned(),
+                serial_num,
+            };
+
+            for family in [
+                &self.inverter_production_watts,
+                &self.inverter_max_production_watts,
+                &self.inverter_dc_voltage_volts,
+                &self.inverter_dc_current_amperes,
+                &self.inverter_temperature_celsius,
+            ] {
+                family.remove(&labels);
+            }
+
+            self.inverter_last_report_timestamp_seconds.remove(&labels);
+            self.inverter_lifetime_watt_hours.remove(&labels);
+        }
+    }
+
+    /// Labels for the inverter, unless it's new and there are too many inverters
+    /// already, in which case it's counted as dropped for the collector.
+    fn inverter_labels(&self, collector: &str, serial_num: String) -> Option<InverterLabels> {
+        let mut last_seen = self
+            .inverter_last_seen
+            .lock()
+            .expect("inverter last seen lock poisoned");
+
+        if !last_seen.contains_key(&serial_num) {
+            if last_seen.len() >= self.max_inverters {
+                self.dropped_inverters
+                    .get_or_create(&CollectorLabels {
+                        collector: collector.to_string(),
+                    })
+                    .inc();
+
+                return None;
+            }
+
+            // Inverters that only show up here are removed once they get stale.
+            last_seen.insert(serial_num.clone(), Instant::now());
+        }
+
+        Some(InverterLabels {
+            name: self.inverter_names.get(&serial_num).cloned(),
+            serial_num,
+        })
+    }
+
+    fn clear_inverters(&self) {
+        for family in [
+            &self.inverter_production_watts,
+            &self.inverter_max_production_watts,
+            &self.inverter_dc_voltage_volts,
+            &self.inverter_dc_current_amperes,
+            &self.inverter_temperature_celsius,
+        ] {
+            family.clear();
+        }
+
+        self.inverter_last_report_timestamp_seconds.clear();
+        self.inverter_lifetime_watt_hours.clear();
+    }
+
+    /// Stores the value reported by the Envoy into the counter, unless it's lower
+    /// than the current one, which happens on meter glitches and firmware updates.
+    fn set_counter(&self, metric: &str, counter: &Counter<f64, AtomicU64>, value: f64) {
+        let result =
+            counter
+                .inner()
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                    (value >= f64::from_bits(current)).then_some(value.to_bits())
+                });
+
+        if result.is_err() {
+            self.counter_regressions
+                .get_or_create(&CounterLabels {
+                    metric: format!("{}_{metric}", self.prefix),
+                })
+                .inc();
+        }
+    }
+
+    fn update_meter(&self, report: &MeterReport) {
+        let labels = MeterLabels {
+            report_type: report.report_type.clone(),
+        };
+
+        let channel = &report.cumulative;
+
+        let gauges = [
+            (&self.meter_voltage_volts, channel.rms_voltage),
+            (&self.meter_current_amperes, channel.rms_current),
+            (&self.meter_frequency_hertz, channel.frequency),
+            (&self.meter_power_factor, channel.power_factor),
+            (&self.reactive_power_var, channel.reactive_power),
+            (&self.apparent_power_va, channel.apparent_power),
+        ];
+
+        for (gauge, value) in gauges {
+            if let Some(value) = value {
+                gauge.get_or_create(&labels).set(value);
+            }
+        }
+    }
+
+    fn remove_meter(&self, report_type: &str) {
+        let labels = MeterLabels {
+            report_type: report_type.to_string(),
+        };
+
+        for gauge in [
+            &self.meter_voltage_volts,
+            &self.meter_current_amperes,
+            &self.meter_frequency_hertz,
+            &self.meter_power_factor,
+            &self.reactive_power_var,
+            &self.apparent_power_va,
+        ] {
+            gauge.remove(&labels);
+        }
+    }
+}
+
+/// Runs every collector once, including those that are turned off, and prints
+/// a table of what the firmware supports. Exits with an error if an Envoy
+/// doesn't accept the credentials or doesn't report production.
+pub(crate) async fn check(states: Vec<(Option<String>, AppState)>) {
+    let width = COLLECTORS
+        .iter()
+        .map(|(name, _, _)| name.len())
+        .max()
+        .unwrap_or_default();
+
+    let mut healthy = true;
+
+    for (_, state) in states {
+        let state = AppState {
+            collectors: Arc::new(COLLECTORS.iter().map(|(name, _, _)| *name).collect()),
+            ..state
+        };
+
+        println!("envoy: {}", state.client.base_url);
+
+        match state.client.info().await {
+            Ok(info) => println!(
+                "serial: {}, part: {}, software: {}",
+                info.labels.serial_num, info.labels.part_num, info.labels.software_version
+            ),
+            Err(err) => println!("info: {err}"),
+        }
+
+        match state.client.check_auth().await {
+            Ok(()) => println!("auth: ok"),
+            Err(err) => {
+                healthy = false;
+                println!("auth: {err}");
+            }
+        }
+
+        // Only the outcome of every collector matters, not the metrics.
+        let _ = scrape(state.clone(), None).await;
+
+        healthy &= state.up.get() == 1;
+
+        let last_errors = state.last_errors.lock().expect("last errors lock poisoned");
+
+        println!();
+        println!(
+            "{:<width$}  {:<11}  {:>8}  ERROR",
+            "COLLECTOR", "STATUS", "LATENCY"
+        );
+
+        for (name, _, _) in COLLECTORS {
+            let labels = CollectorLabels {
+                collector: name.to_string(),
+            };
+
+            let scrapes = state.collector_scrapes.get_or_create(&labels).get();
+            let duration = state
+                .collector_duration_seconds
+                .get_or_create(&labels)
+                .get();
+
+            let (status, error) = match last_errors.get(name) {
+                Some((problem, err)) => (*problem, err.as_str()),
+                None if scrapes == 0 => ("skipped", ""),
+                None => ("ok", ""),
+            };
+
+            println!("{name:<width$}  {status:<11}  {duration:>7.3}s  {error}");
+        }
+
+        println!();
+    }
+
+    if !healthy {
+        std::process::exit(1);
+    }
+}
+
+/// Prints responses keyed by path, and by serial number for Envoys from the
+/// config file, as a single JSON document. Endpoints missing from the firmware
+/// are reported to stderr and left out.
+pub(crate) async fn dump(states: Vec<(Option<String>, AppState)>, args: DumpArgs) {
+    let paths = if args.paths.is_empty() {
+        known_endpoints()
+    } else {
+        args.paths
+    };
+
+    let mut output = serde_json::Map::new();
+
+    for (i, (serial_num, state)) in states.into_iter().enumerate() {
+        if let Err(err) = state.client.check_auth().await {
+            eprintln!(
+                "error authenticating with the envoy at {}: {err}",
+                state.client.base_url
+            );
+            std::process::exit(1);
+        }
+
+        let mut responses = serde_json::Map::new();
+
+        for path in &paths {
+            match state.client.get_once::<serde_json::Value>(path).await {
+                Ok(mut response) => {
+                    if args.redact {
+                        redact(&mut response);
+                    }
+
+                    responses.insert(path.clone(), response);
+                }
+                Err(err) => eprintln!("error requesting {path}: {err}"),
+            }
+        }
+
+        match serial_num {
+            Some(_) if args.redact => {
+                output.insert(format!("envoy{}", i + 1), responses.into());
+            }
+            Some(serial_num) => {
+                output.insert(serial_num, responses.into());
+            }
+            None => output = responses,
+        }
+    }
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&output).expect("error encoding json")
+    );
+}
+
+/// Endpoints requested by collectors, except for `/info`, which is XML.
+fn known_endpoints() -> Vec<String> {
+    [
+        "/api/v1/production",
+        "/api/v1/production/inverters",
+        "/production.json",
+        "/home.json",
+        "/inventory.json",
+        "/ivp/meters",
+        "/ivp/meters/readings",
+        "/ivp/meters/reports/production",
+        "/ivp/meters/reports/consumption",
+        "/ivp/livedata/status",
+        "/ivp/ensemble/inventory",
+        "/ivp/ensemble/power",
+        "/ivp/ensemble/secctrl",
+        "/ivp/ensemble/relay",
+        "/ivp/ensemble/generator",
+        "/ivp/ensemble/dry_contacts",
+        "/ivp/ss/dry_contact_settings",
+        "/ivp/ss/stormguard",
+        "/ivp/ss/pel_settings",
+        "/ivp/peb/devstatus",
+        "/ivp/pdm/device_data",
+        "/installer/agf/index.json",
+        "/admin/lib/date_time_display.json",
+        "/admin/lib/tariff",
+        "/datatab/event_dt.rb",
+    ]
+    .into_iter()
+    .map(String::from)
+    .chain([format!("/ivp/mod/{ENVOY_EID}/mode/power")])
+    .collect()
+}
+
+/// Keys with identifying values, lowercase and without separators.
+const REDACTED_KEYS: &[&str] = &[
+    "serial",
+    "serialnum",
+    "serialnumber",
+    "sn",
+    "mac",
+    "macaddr",
+    "ip",
+    "ipaddr",
+    "gateway",
+    "dns",
+    "ssid",
+    "email",
+    "latitude",
+    "longitude",
+];
+
+/// Replaces values of identifying keys anywhere in the response. This is best
+/// effort, as some endpoints list serial numbers in arrays without keys.
+fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => {
+            for (key, value) in object {
+                let key = key.to_lowercase().replace(['_', '-'], "");
+
+                if REDACTED_KEYS.contains(&key.as_str()) && !value.is_null() {
+                    *value = "REDACTED".into();
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Stops requests to the Envoy after it failed several scrapes in a row,
+/// so that a rebooting gateway doesn't pile up timed out connections.
+#[derive(Clone)]
+pub(crate) struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Arc<std::sync::Mutex<BreakerState>>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Arc::default(),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        let state = self.state.lock().expect("breaker lock poisoned");
+        state.open_until.is_some_and(|until| until > Instant::now())
+    }
+
+    /// Failures are not reset when the breaker trips, so that a single
+    /// failed scrape after the cooldown trips it again.
+    fn record(&self, success: bool) {
+        let mut state = self.state.lock().expect("breaker lock poisoned");
+
+        if success {
+            state.failures = 0;
+            state.open_until = None;
+            return;
+        }
+
+        state.failures += 1;
+
+        if self.threshold > 0 && state.failures >= self.threshold {
+            eprintln!(
+                "envoy failed {} scrapes in a row, pausing requests for {:?}",
+                state.failures, self.cooldown
+            );
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+/// Runs a single collector in its own task, recording how long it took
+/// and whether it succeeded, which is also what the task returns.
+fn collect<F, Fut>(state: &AppState, collector: &'static str, update: F) -> JoinHandle<bool>
+where
+    F: FnOnce(Client, AppState) -> Fut,
+    Fut: Future<Output = Result<(), Error>> + Send + 'static,
+{
+    if !state.collectors.contains(collector) {
+        return spawn(async { true });
+    }
+
+    let labels = CollectorLabels {
+        collector: collector.to_string(),
+    };
+
+    let duration = state
+        .collector_duration_seconds
+        .get_or_create(&labels)
+        .clone();
+    let scrapes = state.collector_scrapes.get_or_create(&labels).clone();
+    let errors = state.collector_errors.get_or_create(&labels).clone();
+    let success = state.collector_success.get_or_create(&labels).clone();
+    let last_success = state
+        .collector_last_success_timestamp_seconds
+        .get_or_create(&labels)
+        .clone();
+
+    // Collectors don't run at all while the Envoy is considered down,
+    // which is reported with `breaker_open` rather than as collector errors.
+    if state.breaker.is_open() {
+        return spawn(async { true });
+    }
+
+    let update = update(state.client.clone(), state.clone());
+    let deadline = state.deadline;
+    let last_errors = state.last_errors.clone();
+
+    spawn(async move {
+        let start = Instant::now();
+
+        // The inner task makes sure that bugs in collectors don't take down
+        // the whole scrape, while errors from the Envoy are returned.
+        let mut task = spawn(update);
+
+        // Whatever isn't done by the deadline would be thrown away by Prometheus.
+        let result = match deadline {
+            Some(deadline) => timeout_at(deadline.into(), &mut task).await,
+            None => Ok((&mut task).await),
+        };
+
+        duration.set(start.elapsed().as_secs_f64());
+        scrapes.inc();
+
+        let error = match result {
+            Ok(Ok(Ok(()))) => {
+                last_success.set(unix_time());
+                success.set(1);
+                last_errors
+                    .lock()
+                    .expect("last errors lock poisoned")
+                    .remove(collector);
+                return true;
+            }
+            Ok(Ok(Err(err))) => {
+                eprintln!("error running collector {collector}: {err}");
+                (err.problem(), err.to_string())
+            }
+            Ok(Err(err)) => {
+                eprintln!("collector {collector} panicked: {err}");
+                ("error", format!("panicked: {err}"))
+            }
+            Err(_) => {
+                task.abort();
+                eprintln!("collector {collector} timed out");
+                ("error", "timed out".to_string())
+            }
+        };
+
+        last_errors
+            .lock()
+            .expect("last errors lock poisoned")
+            .insert(collector, error);
+
+        errors.inc();
+        success.set(0);
+
+        false
+    })
+}
+
+/// Concurrent scrapes wait for the one in flight and reuse its result
+/// instead of sending another set of requests to the Envoy.
+pub(crate) async fn coalesced_scrape(
+    state: &AppState,
+    requested: Instant,
+    deadline: Option<Instant>,
+) -> Result<(), &'static str> {
+    let mut last_scrape = state.last_scrape.lock().await;
+
+    match &*last_scrape {
+        Some(last) if last.finished > requested => last.result,
+        _ => {
+            let result = scrape(state.clone(), deadline).await;
+            last_scrape.replace(Scrape {
+                finished: Instant::now(),
+                result,
+            });
+            result
+        }
+    }
+}
+
+/// Scrapes the Envoy on its own schedule, so that `/metrics` serves the metrics
+/// of the last scrape without waiting for the Envoy, however often it's called.
+pub(crate) async fn poll(state: AppState, interval: Duration) {
+    // Requests made before the first scrape is done wait for it.
+    let mut first = Some(state.last_scrape.lock().await);
+
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticks.tick().await;
+
+        // Collectors still running by the next tick are cancelled.
+        let result = scrape(state.clone(), Some(Instant::now() + interval)).await;
+
+        let scrape = Scrape {
+            finished: Instant::now(),
+            result,
+        };
+
+        match first.take() {
+            Some(mut last_scrape) => last_scrape.replace(scrape),
+            None => state.last_scrape.lock().await.replace(scrape),
+        };
+    }
+}
+
+pub(crate) async fn latest_scrape(state: &AppState) -> Result<(), &'static str> {
+    match &*state.last_scrape.lock().await {
+        Some(last) => last.result,
+        None => Err("no scrape of the envoy has finished yet\n"),
+    }
+}
+
+/// Result of a finished scrape, shared with scrapes that waited for it.
+struct Scrape {
+    finished: Instant,
+    result: Result<(), &'static str>,
+}
+
+/// Runs all collectors. Returns the message for the response if the scrape
+/// failed as a whole.
+async fn scrape(state: AppState, deadline: Option<Instant>) -> Result<(), &'static str> {
+    let state = AppState { deadline, ..state };
+
+    let mut updates = vec![];
+
+    let breaker_open = state.breaker.is_open();
+
+    state.breaker_open.set(breaker_open as i64);
+
+    updates.push(collect(&state, "production", |client, state| async move {
+        // Inverter-derived production is the only source without production CTs.
+        let production_report = match client.production_report().await {
+            Ok(report) if report.is_metered() => Some(report),
+            Ok(_) => None,
+            Err(err) if err.status() == Some(StatusCode::NOT_FOUND) => None,
+            Err(err) => return Err(err),
+        };
+
+        let Some(production_report) = production_report else {
+            let production_summary = client.production_summary().await?;
+
+            // Series from a meter that went away would otherwise stay frozen.
+            state.production_phase_watts.clear();
+            state.remove_meter("production");
+
+            state.production_watts.set(production_summary.watts_now);
+
+            return Ok(());
+        };
+
+        state
+            .production_watts
+            .set(production_report.cumulative.current_watts);
+
+        state.update_meter(&production_report);
+
+        for (phase, line) in PHASES.iter().zip(production_report.lines) {
+            let phase = phase.to_string();
+            state
+                .production_phase_watts
+                .get_or_create(&PhaseLabels { phase })
+                .set(line.current_watts);
+        }
+
+        Ok(())
+    }));
+
+    updates.push(collect(&state, "inverters", |client, state| async move {
+        let inverter_production = client.inverter_production_watts().await?;
+
+        state.remove_stale_inverters(
+            inverter_production
+                .iter()
+                .map(|inverter| inverter.serial_num.clone()),
+        );
+
+        for inverter in inverter_production {
+            let Some(labels) = state.inverter_labels("inverters", inverter.serial_num) else {
+                continue;
+            };
+
+            state
+                .inverter_production_watts
+                .get_or_create(&labels)
+                .set(inverter.last_known_watts);
+            state
+                .inverter_max_production_watts
+                .get_or_create(&labels)
+                .set(inverter.max_known_watts);
+            state
+                .inverter_last_report_timestamp_seconds
+                .get_or_create(&labels)
+                .set(inverter.last_report_timestamp);
+        }
+
+        Ok(())
+    }));
+
+    updates.push(collect(
+        &state,
+        "production_json",
+        |client, state| async move {
+            let cumulative_production = client.cumulative_production().await?;
+
+            let lifetime_watt_hours = cumulative_production
+                .production
+                .iter()
+                .find(|item| item.kind == "inverters")
+                .map(|item| item.lifetime_watt_hours)
+                .unwrap_or_default();
+
+            state.set_counter(
+                "lifetime_watt_hours",
+                &state.lifetime_watt_hours,
+                lifetime_watt_hours,
+            );
+
+            for consumption in cumulative_production.consumption {
+                let labels = MeterLabels {
+                    report_type: consumption.measurement_type,
+                };
+
+                state
+                    .consumption_today_watt_hours
+                    .get_or_create(&labels)
+                    .set(consumption.today_watt_hours);
+                state
+                    .consumption_seven_days_watt_hours
+                    .get_or_create(&labels)
+                    .set(consumption.seven_days_watt_hours);
+                // Net consumption goes down when more energy is exported than
+                // imported, which is covered by the grid import and export counters.
+                if labels.report_type == "total-consumption" {
+                    state.set_counter(
+                        "consumption_lifetime_watt_hours",
+                        &state.consumption_lifetime_watt_hours.get_or_create(&labels),
+                        consumption.lifetime_watt_hours,
+                    );
+                }
+            }
+
+            state.acb_state_info.clear();
+
+            for storage in cumulative_production.storage {
+                if storage.active_count == 0 {
+                    continue;
+                }
+
+                state.acb_power_watts.set(storage.current_watts);
+                state.acb_stored_watt_hours.set(storage.current_watt_hours);
+                state
+                    .acb_state_info
+                    .get_or_create(&AcbStateLabels {
+                        state: storage.state,
+                    })
+                    .set(1);
+            }
+
+            Ok(())
+        },
+    ));
+
+    updates.push(collect(&state, "consumption", |client, state| async move {
+        let consumption_reports = client.consumption_reports().await?;
+
+        for report in consumption_reports {
+            state.update_meter(&report);
+
+            if report.report_type == "net-consumption" {
+                if let Some(delivered) = report.cumulative.delivered_watt_hours {
+                    state.set_counter(
+                        "grid_import_watt_hours",
+                        &state.grid_import_watt_hours,
+                        delivered,
+                    );
+                }
+
+                if let Some(received) = report.cumulative.received_watt_hours {
+                    state.set_counter(
+                        "grid_export_watt_hours",
+                        &state.grid_export_watt_hours,
+                        received,
+                    );
+                }
+            }
+
+            let report_type = report.report_type;
+            state
+                .consumption_watts
+                .get_or_create(&MeterLabels { report_type })
+                .set(report.cumulative.current_watts);
+        }
+
+        Ok(())
+    }));
+
+    updates.push(collect(
+        &state,
+        "ensemble_inventory",
+        |client, state| async move {
+            let ensemble_inventory = client.ensemble_inventory().await?;
+
+            let mut batteries = vec![];
+            let mut enpowers = vec![];
+
+            for item in ensemble_inventory {
+                match item {
+                    EnsembleInventory::Encharge { devices } => batteries.extend(devices),
+                    EnsembleInventory::Enpower { devices } => enpowers.extend(devices),
+                    EnsembleInventory::Other => {}
+                }
+            }
+
+            for battery in batteries {
+                let labels = BatteryLabels {
+                    serial_num: battery.serial_num,
+                };
+
+                let temperature = match battery.temperature_unit.as_str() {
+                    "F" => (battery.temperature - 32.0) * 5.0 / 9.0,
+                    _ => battery.temperature,
+                };
+
+                state
+                    .battery_charge_percent
+                    .get_or_create(&labels)
+                    .set(battery.percent_full);
+                state
+                    .battery_capacity_watt_hours
+                    .get_or_create(&labels)
+                    .set(battery.capacity_watt_hours);
+                state
+                    .battery_available_watt_hours
+                    .get_or_create(&labels)
+                    .set(battery.capacity_watt_hours * battery.percent_full / 100.0);
+                state
+                    .battery_temperature_celsius
+                    .get_or_create(&labels)
+                    .set(temperature);
+            }
+
+            state.grid_state_info.clear();
+
+            for enpower in enpowers {
+                let connected = enpower.mains_oper_state == "closed";
+
+                state
+                    .grid_connected
+                    .get_or_create(&EnpowerLabels {
+                        serial_num: enpower.serial_num.clone(),
+                    })
+                    .set(connected as i64);
+                state
+                    .grid_state_info
+                    .get_or_create(&GridStateLabels {
+                        serial_num: enpower.serial_num,
+                        grid_state: enpower.grid_mode,
+                    })
+                    .set(1);
+            }
+
+            Ok(())
+        },
+    ));
+
+    updates.push(collect(
+        &state,
+        "ensemble_power",
+        |client, state| async move {
+            let battery_power = client.battery_power().await?;
+
+            if battery_power.is_empty() {
+                return Ok(());
+            }
+
+            let mut site_power = 0.0;
+
+            for battery in battery_power {
+                let labels = BatteryLabels {
+                    serial_num: battery.serial_num,
+                };
+
+                // The Envoy reports discharge as positive power.
+                let real_power = -battery.real_power_milliwatts / 1000.0;
+
+                site_power += real_power;
+
+                state
+                    .battery_real_power_watts
+                    .get_or_create(&labels)
+                    .set(real_power);
+                state
+                    .battery_apparent_power_va
+                    .get_or_create(&labels)
+                    .set(battery.apparent_power_millivolt_amperes / 1000.0);
+            }
+
+            state.battery_charge_watts.set(site_power.max(0.0));
+            state.battery_discharge_watts.set((-site_power).max(0.0));
+
+            Ok(())
+        },
+    ));
+
+    updates.push(collect(
+        &state,
+        "production_summary",
+        |client, state| async move {
+            let production_summary = client.production_summary().await?;
+
+            state
+                .production_today_watt_hours
+                .set(production_summary.today_watt_hours);
+            state
+                .production_seven_days_watt_hours
+                .set(production_summary.seven_days_watt_hours);
+
+            Ok(())
+        },
+    ));
+
+    updates.push(collect(&state, "inventory", |client, state| async move {
+        let mut inventory = client.inventory().await?;
+
+        let stale_inverters = state
+            .stale_inverters
+            .lock()
+            .expect("stale inverters lock poisoned")
+            .clone();
+
+        for item in inventory.iter_mut().filter(|item| item.kind == "PCU") {
+            item.devices
+                .retain(|device| !stale_inverters.contains(&device.serial_num));
+        }
+
+        state.device_status_info.clear();
+
+        let inverters = inventory
+            .iter()
+            .filter(|item| item.kind == "PCU")
+            .flat_map(|item| &item.devices);
+
+        let (mut total, mut producing, mut communicating) = (0, 0, 0);
+
+        for inverter in inverters {
+            total += 1;
+            producing += inverter.producing as i64;
+            communicating += inverter.communicating as i64;
+        }
+
+        state.inverter_info.clear();
+
+        for inverter in inventory
+            .iter()
+            .filter(|item| item.kind == "PCU")
+            .flat_map(|item| &item.devices)
+        {
+            state
+                .inverter_info
+                .get_or_create(&InverterInfoLabels {
+                    serial_num: inverter.serial_num.clone(),
+                    part_num: inverter.part_num.clone(),
+                    img_load_date: inverter.img_load_date.clone(),
+                    firmware: inverter.img_pnum_running.clone(),
+                })
+                .set(1);
+        }
+
+        state.inverters_total.set(total);
+        state.inverters_producing.set(producing);
+        state.inverters_communicating.set(communicating);
+
+        for item in inventory {
+            for device in item.devices {
+                let labels = DeviceLabels {
+                    serial_num: device.serial_num,
+                    device_type: item.kind.clone(),
+                };
+
+                state
+                    .device_producing
+                    .get_or_create(&labels)
+                    .set(device.producing as i64);
+                state
+                    .device_communicating
+                    .get_or_create(&labels)
+                    .set(device.communicating as i64);
+                state
+                    .device_provisioned
+                    .get_or_create(&labels)
+                    .set(device.provisioned as i64);
+
+                for device_status in device.device_status {
+                    state
+                        .device_status_info
+                        .get_or_create(&DeviceStatusLabels {
+                            serial_num: labels.serial_num.clone(),
+                            device_type: labels.device_type.clone(),
+                            device_status,
+                        })
+                        .set(1);
+                }
+
+                if let Some(relay) = device.relay {
+                    let labels = RelayLabels {
+                        serial_num: labels.serial_num.clone(),
+                    };
+
+                    state
+                        .relay_closed
+                        .get_or_create(&labels)
+                        .set((relay == "closed") as i64);
+                    state
+                        .relay_forced
+                        .get_or_create(&labels)
+                        .set(device.forced as i64);
+
+                    let lines = [
+                        device.line1_connected,
+                        device.line2_connected,
+                        device.line3_connected,
+                    ];
+
+                    for (line, connected) in lines.iter().enumerate().take(device.line_count) {
+                        state
+                            .relay_line_connected
+                            .get_or_create(&RelayLineLabels {
+                                serial_num: labels.serial_num.clone(),
+                                line: (line + 1).to_string(),
+                            })
+                            .set(*connected as i64);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }));
+
+    updates.push(collect(
+        &state,
+        "inverter_status",
+        |client, state| async move {
+            let inverter_status = client.inverter_status().await?;
+
+            for inverter in inverter_status {
+                let Some(labels) = state.inverter_labels("inverter_status", inverter.serial_num)
+                else {
+                    continue;
+                };
+
+                state
+                    .inverter_dc_voltage_volts
+                    .get_or_create(&labels)
+                    .set(inverter.dc_voltage);
+                state
+                    .inverter_dc_current_amperes
+                    .get_or_create(&labels)
+                    .set(inverter.dc_current);
+                state
+                    .inverter_temperature_celsius
+                    .get_or_create(&labels)
+                    .set(inverter.temperature);
+            }
+
+            Ok(())
+        },
+    ));
+
+    updates.push(collect(&state, "home", |client, state| async move {
+        let home = client.home().await?;
+
+        if let Some(size) = parse_size(&home.db_size) {
+            state.database_size_bytes.set(size);
+        }
+
+        if let Some(percent) = parse_number(&home.db_percent_full) {
+            state.database_full_percent.set(percent);
+        }
+
+        state
+            .last_enlighten_report_timestamp_seconds
+            .set(home.network.last_enlighten_report_time);
+
+        state.internet_connected.set(home.network.web_comm as i64);
+
+        state.network_interface_info.clear();
+
+        for interface in home.network.interfaces {
+            let primary = interface.interface == home.network.primary_interface;
+
+            state
+                .network_interface_info
+                .get_or_create(&NetworkInterfaceInfoLabels {
+                    interface: interface.interface.clone(),
+                    interface_type: interface.kind.clone(),
+                    ip: interface.ip,
+                    dhcp: interface.dhcp.to_string(),
+                    primary: primary.to_string(),
+                })
+                .set(1);
+            if interface.kind == "cellular" && !interface.imei.is_empty() {
+                let labels = ModemLabels {
+                    imei: interface.imei.clone(),
+                };
+
+                state
+                    .cellular_signal_strength
+                    .get_or_create(&labels)
+                    .set(interface.signal_strength);
+                state
+                    .cellular_connected
+                    .get_or_create(&labels)
+                    .set((interface.status == "connected") as i64);
+            }
+
+            let labels = NetworkInterfaceLabels {
+                interface: interface.interface,
+            };
+
+            state
+                .network_interface_carrier
+                .get_or_create(&labels)
+                .set(interface.carrier as i64);
+
+            // Wired interfaces report a meaningless signal strength of 1 out of 1.
+            if interface.kind != "ethernet" {
+                state
+                    .network_interface_signal_strength
+                    .get_or_create(&labels)
+                    .set(interface.signal_strength);
+                state
+                    .network_interface_signal_strength_max
+                    .get_or_create(&labels)
+                    .set(interface.signal_strength_max);
+            }
+        }
+
+        Ok(())
+    }));
+
+    updates.push(collect(&state, "events", |client, state| async move {
+        let events = client.events().await?;
+
+        let mut last_event_id = state
+            .last_event_id
+            .lock()
+            .expect("last event id lock poisoned");
+
+        let mut new_events = new_events(events, *last_event_id);
+
+        if last_event_id.is_some() {
+            for event in &new_events {
+                state
+                    .events
+                    .get_or_create(&EventLabels {
+                        device_type: event.device_type.clone(),
+                    })
+                    .inc();
+            }
+        }
+
+        let latest = new_events.pop();
+        let latest_id = latest.as_ref().map(|event| event.id);
+        *last_event_id = latest_id.or(*last_event_id).or(Some(0));
+
+        if let Some(event) = latest {
+            state.last_event_info.clear();
+            state
+                .last_event_info
+                .get_or_create(&LastEventLabels {
+                    event: event.description,
+                    device_type: event.device_type,
+                    serial_num: event.serial_num,
+                })
+                .set(1);
+        }
+
+        Ok(())
+    }));
+
+    updates.push(collect(&state, "tariff", |client, state| async move {
+        let Some(tariff) = client.tariff().await? else {
+            return Ok(());
+        };
+
+        state.tariff_info.clear();
+        state
+            .tariff_info
+            .get_or_create(&TariffLabels {
+                currency: tariff.currency.code,
+            })
+            .set(1);
+
+        if let Some(storage_settings) = tariff.storage_settings {
+            state
+                .battery_reserve_percent
+                .set(storage_settings.reserved_soc);
+            state
+                .battery_charge_from_grid_enabled
+                .set(storage_settings.charge_from_grid as i64);
+        }
+
+        state.battery_charge_from_grid_window_info.clear();
+
+        for season in &tariff.seasons {
+            for days in &season.days {
+                let (Some(start), Some(duration)) =
+                    (&days.must_charge_start, &days.must_charge_duration)
+                else {
+                    continue;
+                };
+
+                state
+                    .battery_charge_from_grid_window_info
+                    .get_or_create(&ChargeWindowLabels {
+                        season: season.id.clone(),
+                        days: days.days.clone(),
+                        start: json_label(start),
+                        duration: json_label(duration),
+                    })
+                    .set(1);
+            }
+        }
+
+        state.tariff_rate.clear();
+
+        if let Some(single_rate) = tariff.single_rate {
+            for (direction, rate) in [("buy", single_rate.rate), ("sell", single_rate.sell)] {
+                if let Some(rate) = parse_number(&rate) {
+                    state
+                        .tariff_rate
+                        .get_or_create(&TariffRateLabels {
+                            direction: direction.to_string(),
+                        })
+                        .set(rate);
+                }
+            }
+        }
+
+        state.tariff_period_rate.clear();
+
+        for (direction, seasons) in [("buy", tariff.seasons), ("sell", tariff.seasons_sell)] {
+            for season in seasons {
+                for days in season.days {
+                    for period in days.periods {
+                        let Some(rate) = parse_number(&period.rate) else {
+                            continue;
+                        };
+
+                        state
+                            .tariff_period_rate
+                            .get_or_create(&TariffPeriodLabels {
+                                direction: direction.to_string(),
+                                season: season.id.clone(),
+                                days: days.days.clone(),
+                                period: period.id,
+                                start: json_label(&period.start),
+                            })
+                            .set(rate);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }));
+
+    updates.push(collect(&state, "meters", |client, state| async move {
+        let meters = client.meters().await?;
+
+        state.meter_info.clear();
+
+        let mut measurement_types = HashMap::new();
+
+        for meter in meters {
+            measurement_types.insert(meter.eid, meter.measurement_type.clone());
+
+            state
+                .meter_info
+                .get_or_create(&MeterInfoLabels {
+                    eid: meter.eid.to_string(),
+                    state: meter.state,
+                    measurement_type: meter.measurement_type,
+                    metering_status: meter.metering_status,
+                    phase_mode: meter.phase_mode,
+                    phase_count: meter.phase_count.to_string(),
+                })
+                .set(1);
+        }
+
+        let readings = client.meter_readings().await?;
+
+        for reading in readings {
+            let labels = MeterReadingLabels {
+                eid: reading.eid.to_string(),
+                measurement_type: measurement_types
+                    .get(&reading.eid)
+                    .cloned()
+                    .unwrap_or_default(),
+            };
+
+            state
+                .meter_reading_active_power_watts
+                .get_or_create(&labels)
+                .set(reading.active_power);
+            state
+                .meter_reading_current_amperes
+                .get_or_create(&labels)
+                .set(reading.current);
+            state
+                .meter_reading_voltage_volts
+                .get_or_create(&labels)
+                .set(reading.voltage);
+            state
+                .meter_reading_frequency_hertz
+                .get_or_create(&labels)
+                .set(reading.frequency);
+        }
+
+        Ok(())
+    }));
+
+    updates.push(collect(&state, "livedata", |client, state| async move {
+        let Some(livedata) = client.livedata().await? else {
+            return Ok(());
+        };
+
+        let meters = livedata.meters;
+
+        for (source, meter) in [
+            ("pv", meters.pv),
+            ("storage", meters.storage),
+            ("grid", meters.grid),
+            ("load", meters.load),
+        ] {
+            state
+                .livedata_power_watts
+                .get_or_create(&LivedataLabels {
+                    source: source.to_string(),
+                })
+                .set(meter.power_milliwatts / 1000.0);
+        }
+
+        Ok(())
+    }));
+
+    updates.push(collect(
+        &state,
+        "dry_contacts",
+        |client, state| async move {
+            let dry_contacts = client.dry_contacts().await?;
+
+            for dry_contact in dry_contacts {
+                state
+                    .dry_contact_closed
+                    .get_or_create(&DryContactLabels { id: dry_contact.id })
+                    .set((dry_contact.status == "closed") as i64);
+            }
+
+            let dry_contact_settings = client.dry_contact_settings().await?;
+
+            state.dry_contact_info.clear();
+
+            for dry_contact in dry_contact_settings {
+                state
+                    .dry_contact_info
+                    .get_or_create(&DryContactInfoLabels {
+                        id: dry_contact.id,
+                        load_name: dry_contact.load_name,
+                        mode: dry_contact.mode,
+                    })
+                    .set(1);
+            }
+
+            Ok(())
+        },
+    ));
+
+    updates.push(collect(&state, "generator", |client, state| async move {
+        let Some(generator) = client.generator().await? else {
+            return Ok(());
+        };
+
+        state.generator_state_info.clear();
+        state
+            .generator_state_info
+            .get_or_create(&GeneratorStateLabels {
+                state: generator.oper_state,
+                mode: generator.admin_mode,
+            })
+            .set(1);
+
+        state
+            .generator_start_charge_percent
+            .set(generator.start_soc);
+        state.generator_stop_charge_percent.set(generator.stop_soc);
+
+        if let Some(runtime) = generator.runtime {
+            state.generator_runtime_seconds.set(runtime);
+        }
+
+        Ok(())
+    }));
+
+    updates.push(collect(
+        &state,
+        "grid_profile",
+        |client, state| async move {
+            let Some(grid_profile) = client.grid_profile().await? else {
+                return Ok(());
+            };
+
+            state.grid_profile_info.clear();
+            state.grid_profile_info.get_or_create(&grid_profile).set(1);
+
+            Ok(())
+        },
+    ));
+
+    updates.push(collect(
+        &state,
+        "export_limit",
+        |client, state| async move {
+            let Some(export_limit) = client.export_limit().await? else {
+                return Ok(());
+            };
+
+            state.export_limit_enabled.set(export_limit.enable as i64);
+            state.export_limit_watts.set(export_limit.limit_watts);
+
+            Ok(())
+        },
+    ));
+
+    updates.push(collect(&state, "power_mode", |client, state| async move {
+        let Some(power_mode) = client.power_mode().await? else {
+            return Ok(());
+        };
+
+        state
+            .production_forced_off
+            .set(power_mode.power_forced_off as i64);
+
+        Ok(())
+    }));
+
+    updates.push(collect(
+        &state,
+        "inverter_lifetime",
+        |client, state| async move {
+            let inverter_lifetime = client.inverter_lifetime_watt_hours().await?;
+
+            for (serial_num, lifetime_watt_hours) in inverter_lifetime {
+                let Some(labels) = state.inverter_labels("inverter_lifetime", serial_num) else {
+                    continue;
+                };
+
+                state.set_counter(
+                    "inverter_lifetime_watt_hours",
+                    &state.inverter_lifetime_watt_hours.get_or_create(&labels),
+                    lifetime_watt_hours,
+                );
+            }
+
+            Ok(())
+        },
+    ));
+
+    updates.push(collect(&state, "time", |client, state| async move {
+        let info = client.info().await?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time is before unix epoch")
+            .as_secs_f64();
+
+        if let Some(time) = info.time {
+            state.time_seconds.set(time);
+            state.clock_offset_seconds.set(time - now);
+        }
+
+        if let Some(date_time) = client.date_time().await? {
+            state.ntp_enabled.set(date_time.ntp_enabled as i64);
+        }
+
+        Ok(())
+    }));
+
+    updates.push(collect(&state, "storm_guard", |client, state| async move {
+        let Some(storm_guard) = client.storm_guard().await? else {
+            return Ok(());
+        };
+
+        state
+            .storm_guard_enabled
+            .set((storm_guard.state == "enabled") as i64);
+        state.storm_alert_active.set(storm_guard.alert as i64);
+
+        Ok(())
+    }));
+
+    updates.push(collect(&state, "secctrl", |client, state| async move {
+        let Some(security_control) = client.security_control().await? else {
+            return Ok(());
+        };
+
+        state
+            .battery_aggregate_charge_percent
+            .set(security_control.aggregate_soc);
+        state
+            .battery_aggregate_capacity_watt_hours
+            .set(security_control.max_energy);
+        state
+            .battery_backup_watt_hours
+            .set(security_control.backup_energy);
+
+        Ok(())
+    }));
+
+    updates.push(collect(
+        &state,
+        "enpower_relays",
+        |client, state| async move {
+            let Some(relays) = client.enpower_relays().await? else {
+                return Ok(());
+            };
+
+            let states = [
+                ("mains", relays.mains_oper_state == "closed"),
+                ("der1", relays.der1_state != 0),
+                ("der2", relays.der2_state != 0),
+                ("der3", relays.der3_state != 0),
+            ];
+
+            for (relay, closed) in states {
+                state
+                    .enpower_relay_closed
+                    .get_or_create(&EnpowerRelayLabels {
+                        relay: relay.to_string(),
+                    })
+                    .set(closed as i64);
+            }
+
+            Ok(())
+        },
+    ));
+
+    let results = join_all(updates)
+        .await
+        .into_iter()
+        .map(|result| result.unwrap_or(false))
+        .collect::<Vec<_>>();
+
+    let success = results.iter().all(|success| *success);
+
+    // Only set once every collector is done, so that metrics served while
+    // the Envoy is being scraped don't report it as down. The production
+    // collector is the first one, and it doesn't run while the breaker is open.
+    let up = !breaker_open && state.collectors.contains("production") && results[0];
+    state.up.set(up as i64);
+
+    if !breaker_open {
+        state.breaker.record(state.up.get() == 1);
+    }
+
+    if state.up.get() == 1 {
+        state
+            .last_success
+            .lock()
+            .expect("last success lock poisoned")
+            .replace(Instant::now());
+    }
+
+    if !success && state.fail_on_error {
+        return Err("error collecting metrics from the envoy\n");
+    }
+
+    if let Some(expiry) = state.client.token_expiry() {
+        state.token_expiry_timestamp_seconds.set(expiry);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub(crate) struct EnvoyInfo {
+    pub(crate) labels: InfoLabels,
+    /// Current time on the Envoy as a unix timestamp.
+    pub(crate) time: Option<f64>,
+}
+
+/// Returns the contents of the first occurrence of a simple XML tag.
+pub(crate) fn xml_tag<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{name}>");
+    let close = format!("</{name}>");
+
+    let start = body.find(&open)? + open.len();
+    let end = body[start..].find(&close)? + start;
+
+    Some(body[start..end].trim())
+}
+
+/// Depending on firmware, numbers can be reported as strings.
+fn parse_number(value: &serde_json::Value) -> Option<f64> {
+    match value {
+        serde_json::Value::Number(number) => number.as_f64(),
+        serde_json::Value::String(string) => string.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Formats a value that can be either a string or a number as a label.
+fn json_label(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(string) => string.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Parses sizes like `16 MB`, falling back to plain numbers of bytes.
+fn parse_size(value: &serde_json::Value) -> Option<f64> {
+    let serde_json::Value::String(string) = value else {
+        return parse_number(value);
+    };
+
+    let (number, unit) = string
+        .trim()
+        .split_once(' ')
+        .unwrap_or((string.trim(), "B"));
+
+    let multiplier = match unit {
+        "B" => 1.0,
+        "KB" => 1024.0,
+        "MB" => 1024.0 * 1024.0,
+        "GB" => 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+
+    number.parse::<f64>().ok().map(|number| number * multiplier)
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Meter {
+    eid: u64,
+    state: String,
+    #[serde(rename = "measurementType")]
+    measurement_type: String,
+    #[serde(rename = "meteringStatus")]
+    metering_status: String,
+    #[serde(rename = "phaseMode")]
+    phase_mode: String,
+    #[serde(rename = "phaseCount")]
+    phase_count: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct MeterReading {
+    eid: u64,
+    #[serde(rename = "activePower")]
+    active_power: f64,
+    current: f64,
+    voltage: f64,
+    #[serde(rename = "freq")]
+    frequency: f64,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct MeterReport {
+    #[serde(rename = "reportType")]
+    report_type: String,
+    cumulative: MeterReportChannel,
+    #[serde(default)]
+    lines: Vec<MeterReportChannel>,
+}
+
+impl MeterReport {
+    /// Without production CTs the report has zero voltage, which never happens
+    /// on a metered grid connected Envoy. Some metered firmware leaves the
+    /// voltage out, so only an explicit zero means there is no meter.
+    fn is_metered(&self) -> bool {
+        self.cumulative.rms_voltage != Some(0.0)
+    }
+}
+
+/// Only the power is reported by every firmware, metrics for other fields
+/// are skipped when they are missing.
+#[derive(Deserialize, Debug)]
+struct MeterReportChannel {
+    #[serde(rename = "currW")]
+    current_watts: f64,
+    #[serde(rename = "rmsVoltage")]
+    rms_voltage: Option<f64>,
+    #[serde(rename = "rmsCurrent")]
+    rms_current: Option<f64>,
+    #[serde(rename = "freqHz")]
+    frequency: Option<f64>,
+    #[serde(rename = "pwrFactor")]
+    power_factor: Option<f64>,
+    #[serde(rename = "reactPwr")]
+    reactive_power: Option<f64>,
+    #[serde(rename = "apprntPwr")]
+    apparent_power: Option<f64>,
+    #[serde(rename = "whDlvdCum")]
+    delivered_watt_hours: Option<f64>,
+    #[serde(rename = "whRcvdCum")]
+    received_watt_hours: Option<f64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct ProductionSummary {
+    #[serde(rename = "wattsNow")]
+    watts_now: f64,
+    #[serde(rename = "wattHoursToday")]
+    today_watt_hours: f64,
+    #[serde(rename = "wattHoursSevenDays")]
+    seven_days_watt_hours: f64,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct InverterProduction {
+    #[serde(rename = "serialNumber")]
+    serial_num: String,
+    #[serde(rename = "lastReportWatts")]
+    last_known_watts: f64,
+    #[serde(rename = "maxReportWatts")]
+    max_known_watts: f64,
+    #[serde(rename = "lastReportDate")]
+    last_report_timestamp: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct GridProfileResponse {
+    pub(crate) selected_profile: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct ExportLimitSettings {
+    enable: bool,
+    #[serde(rename = "limit_value_W", default)]
+    limit_watts: f64,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct PowerMode {
+    #[serde(rename = "powerForcedOff")]
+    power_forced_off: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct DeviceData {
+    #[serde(rename = "devName")]
+    pub(crate) dev_name: String,
+    #[serde(rename = "sn")]
+    pub(crate) serial_num: String,
+    pub(crate) channels: Vec<DeviceDataChannel>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct DeviceDataChannel {
+    pub(crate) lifetime: DeviceDataLifetime,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct DeviceDataLifetime {
+    #[serde(rename = "joulesProduced")]
+    pub(crate) joules_produced: f64,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct DeviceStatusResponse {
+    pub(crate) pcu: DeviceStatusTable,
+}
+
+/// Device status is reported as a table with column names listed separately.
+#[derive(Deserialize, Debug)]
+pub(crate) struct DeviceStatusTable {
+    fields: Vec<String>,
+    values: Vec<Vec<serde_json::Value>>,
+}
+
+impl DeviceStatusTable {
+    pub(crate) fn rows(&self) -> impl Iterator<Item = HashMap<&str, &serde_json::Value>> {
+        self.values.iter().map(|values| {
+            self.fields
+                .iter()
+                .map(String::as_str)
+                .zip(values.iter())
+                .collect()
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct InverterStatus {
+    pub(crate) serial_num: String,
+    pub(crate) dc_voltage: f64,
+    pub(crate) dc_current: f64,
+    pub(crate) temperature: f64,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct CumulativeProductionResponse {
+    production: Vec<CumulativeProductionResponseItem>,
+    #[serde(default)]
+    consumption: Vec<CumulativeConsumptionItem>,
+    #[serde(default)]
+    storage: Vec<CumulativeProductionStorageItem>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CumulativeProductionResponseItem {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(rename = "whLifetime")]
+    lifetime_watt_hours: f64,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct DateTimeResponse {
+    pub(crate) date_time: DateTime,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct DateTime {
+    #[serde(default)]
+    ntp_enabled: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct HomeResponse {
+    db_size: serde_json::Value,
+    db_percent_full: serde_json::Value,
+    network: HomeNetwork,
+}
+
+#[derive(Deserialize, Debug)]
+struct HomeNetwork {
+    last_enlighten_report_time: i64,
+    web_comm: bool,
+    primary_interface: String,
+    interfaces: Vec<HomeNetworkInterface>,
+}
+
+#[derive(Deserialize, Debug)]
+struct HomeNetworkInterface {
+    #[serde(rename = "type")]
+    kind: String,
+    interface: String,
+    #[serde(default)]
+    ip: String,
+    #[serde(default)]
+    dhcp: bool,
+    #[serde(default)]
+    carrier: bool,
+    #[serde(default)]
+    signal_strength: i64,
+    #[serde(default)]
+    signal_strength_max: i64,
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    imei: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Inventory {
+    #[serde(rename = "type")]
+    kind: String,
+    devices: Vec<InventoryDevice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct InventoryDevice {
+    serial_num: String,
+    #[serde(default)]
+    part_num: String,
+    #[serde(default)]
+    img_load_date: String,
+    #[serde(default)]
+    img_pnum_running: String,
+    #[serde(default)]
+    device_status: Vec<String>,
+    #[serde(default)]
+    producing: bool,
+    #[serde(default)]
+    communicating: bool,
+    #[serde(default)]
+    provisioned: bool,
+    /// Only present for IQ Relays.
+    relay: Option<String>,
+    #[serde(default)]
+    forced: bool,
+    #[serde(rename = "line-count", default)]
+    line_count: usize,
+    #[serde(rename = "line1-connected", default)]
+    line1_connected: bool,
+    #[serde(rename = "line2-connected", default)]
+    line2_connected: bool,
+    #[serde(rename = "line3-connected", default)]
+    line3_connected: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct EventsResponse {
+    #[serde(rename = "aaData")]
+    pub(crate) data: Vec<Vec<serde_json::Value>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Event {
+    id: u64,
+    description: String,
+    serial_num: String,
+    device_type: String,
+}
+
+/// Events logged since the last poll, oldest first. Everything is new on
+/// the first poll, but only the latest event is used then.
+fn new_events(mut events: Vec<Event>, last_event_id: Option<u64>) -> Vec<Event> {
+    events.retain(|event| last_event_id.is_none_or(|last| event.id > last));
+    events.sort_by_key(|event| event.id);
+    events
+}
+
+impl Event {
+    /// Events are rows of `[id, description, serial, device type, date]`.
+    pub(crate) fn from_row(row: Vec<serde_json::Value>) -> Option<Self> {
+        let string = |index: usize| -> Option<String> {
+            row.get(index)?
+                .as_str()
+                .map(|value| value.trim().to_string())
+        };
+
+        Some(Self {
+            id: parse_number(row.first()?)? as u64,
+            description: string(1)?,
+            serial_num: string(2).unwrap_or_default(),
+            device_type: string(3).unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct TariffResponse {
+    pub(crate) tariff: Tariff,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Tariff {
+    currency: TariffCurrency,
+    single_rate: Option<TariffSingleRate>,
+    storage_settings: Option<StorageSettings>,
+    #[serde(default)]
+    seasons: Vec<TariffSeason>,
+    #[serde(default)]
+    seasons_sell: Vec<TariffSeason>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TariffCurrency {
+    code: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct StorageSettings {
+    reserved_soc: f64,
+    #[serde(default)]
+    charge_from_grid: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct TariffSingleRate {
+    rate: serde_json::Value,
+    sell: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct TariffSeason {
+    id: String,
+    days: Vec<TariffDays>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TariffDays {
+    days: String,
+    periods: Vec<TariffPeriod>,
+    /// Start of the window to charge batteries from the grid in minutes since midnight.
+    must_charge_start: Option<serde_json::Value>,
+    /// Duration of the window to charge batteries from the grid in minutes.
+    must_charge_duration: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TariffPeriod {
+    id: String,
+    /// Minutes since midnight, reported either as a number or a string.
+    start: serde_json::Value,
+    rate: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct LivedataResponse {
+    meters: LivedataMeters,
+}
+
+#[derive(Deserialize, Debug)]
+struct LivedataMeters {
+    pv: LivedataMeter,
+    storage: LivedataMeter,
+    grid: LivedataMeter,
+    load: LivedataMeter,
+}
+
+#[derive(Deserialize, Debug)]
+struct LivedataMeter {
+    #[serde(rename = "agg_p_mw")]
+    power_milliwatts: f64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+pub(crate) enum EnsembleInventory {
+    #[serde(rename = "ENCHARGE")]
+    Encharge { devices: Vec<EnchargeDevice> },
+    #[serde(rename = "ENPOWER")]
+    Enpower { devices: Vec<EnpowerDevice> },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct EnchargeDevice {
+    serial_num: String,
+    #[serde(rename = "percentFull")]
+    percent_full: f64,
+    #[serde(rename = "encharge_capacity")]
+    capacity_watt_hours: f64,
+    temperature: f64,
+    #[serde(default)]
+    temperature_unit: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct EnpowerDevice {
+    serial_num: String,
+    mains_oper_state: String,
+    #[serde(rename = "Enpwr_grid_mode")]
+    grid_mode: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct DryContactsResponse<T> {
+    pub(crate) dry_contacts: Vec<T>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct DryContact {
+    id: String,
+    status: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct DryContactSettings {
+    id: String,
+    #[serde(default)]
+    load_name: String,
+    #[serde(default)]
+    mode: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Generator {
+    /// One of `off`, `running` or `exercising`.
+    oper_state: String,
+    #[serde(default)]
+    admin_mode: String,
+    start_soc: f64,
+    stop_soc: f64,
+    runtime: Option<f64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct StormGuard {
+    #[serde(rename = "stormGuardState")]
+    state: String,
+    #[serde(rename = "stormAlert", default)]
+    alert: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct SecurityControl {
+    #[serde(rename = "agg_soc")]
+    aggregate_soc: f64,
+    #[serde(rename = "Max_energy")]
+    max_energy: f64,
+    #[serde(rename = "ENC_agg_backup_energy", default)]
+    backup_energy: f64,
+}
+
+/// The main relay connects the site to the grid, while DER relays
+/// connect branches with distributed energy resources like PV or batteries.
+#[derive(Deserialize, Debug)]
+pub(crate) struct EnpowerRelays {
+    mains_oper_state: String,
+    #[serde(default)]
+    der1_state: i64,
+    #[serde(default)]
+    der2_state: i64,
+    #[serde(default)]
+    der3_state: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct EnsemblePowerResponse {
+    // Not a typo, the trailing colon is really there.
+    #[serde(rename = "devices:")]
+    pub(crate) devices: Vec<BatteryPower>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct BatteryPower {
+    serial_num: String,
+    #[serde(rename = "real_power_mw")]
+    real_power_milliwatts: f64,
+    #[serde(rename = "apparent_power_mva")]
+    apparent_power_millivolt_amperes: f64,
+}
+
+#[derive(Deserialize, Debug)]
+struct CumulativeConsumptionItem {
+    #[serde(rename = "measurementType")]
+    measurement_type: String,
+    #[serde(rename = "whToday")]
+    today_watt_hours: f64,
+    #[serde(rename = "whLastSevenDays")]
+    seven_days_watt_hours: f64,
+    #[serde(rename = "whLifetime")]
+    lifetime_watt_hours: f64,
+}
+
+#[derive(Deserialize, Debug)]
+struct CumulativeProductionStorageItem {
+    #[serde(rename = "activeCount")]
+    active_count: u64,
+    #[serde(rename = "wNow")]
+    current_watts: f64,
+    #[serde(rename = "whNow")]
+    current_watt_hours: f64,
+    state: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use prometheus_client::{encoding::text::encode, registry::Registry};
+
+    use crate::{auth::Auth, client::EnvoyTls, metrics::Envoys};
+
+    fn state(max_inverters: usize) -> AppState {
+        let client = Client::new(
+            "http://127.0.0.1",
+            Auth::Token("token".to_string()),
+            EnvoyTls::Any,
+            None,
+            Duration::from_secs(1),
+            0,
+        )
+        .unwrap();
+
+        let options = CollectorOptions {
+            collectors: HashSet::new(),
+            inverter_stale_after: Duration::from_secs(3600),
+            max_inverters,
+            inverter_names: Arc::new(HashMap::from([("2".to_string(), "roof".to_string())])),
+            fail_on_error: false,
+            prefix: "enphase_envoy".to_string(),
+        };
+
+        AppState::new(
+            client,
+            vec![],
+            &options,
+            CircuitBreaker::new(0, Duration::ZERO),
+        )
+    }
+
+    fn encoded(state: &AppState) -> String {
+        let mut registry = Registry::with_prefix(&state.prefix);
+        registry.register_collector(Box::new(Envoys(vec![state.registry.clone()])));
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        buffer
+    }
+
+    fn event(id: u64) -> Event {
+        Event::from_row(vec![
+            id.into(),
+            "Grid Instability".into(),
+            "122233445566".into(),
+            "PCU".into(),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn new_events_since_last_poll() {
+        let ids = |events: Vec<Event>| events.iter().map(|event| event.id).collect::<Vec<_>>();
+
+        assert_eq!(
+            ids(new_events(vec![event(3), event(1), event(2)], None)),
+            [1, 2, 3]
+        );
+        assert_eq!(
+            ids(new_events(vec![event(3), event(1), event(4)], Some(2))),
+            [3, 4]
+        );
+        assert!(new_events(vec![event(1), event(2)], Some(2)).is_empty());
+    }
+
+    #[test]
+    fn event_rows() {
+        let event = Event::from_row(vec![
+            "12".into(),
+            " Grid Instability ".into(),
+            serde_json::Value::Null,
+        ])
+        .unwrap();
+
+        assert_eq!(event.id, 12);
+        assert_eq!(event.description, "Grid Instability");
+        assert_eq!(event.serial_num, "");
+        assert_eq!(event.device_type, "");
+
+        assert!(Event::from_row(vec![]).is_none());
+        assert!(Event::from_row(vec!["twelve".into(), "Grid Instability".into()]).is_none());
+        assert!(Event::from_row(vec![12.into()]).is_none());
+    }
+
+    #[test]
+    fn meter_report_without_optional_fields() {
+        let report = serde_json::from_str::<MeterReport>(
+            r#"{"reportType": "production", "cumulative": {"currW": 1500.5}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(report.cumulative.current_watts, 1500.5);
+        assert_eq!(report.cumulative.rms_voltage, None);
+        assert_eq!(report.cumulative.delivered_watt_hours, None);
+        assert!(report.lines.is_empty());
+    }
+
+    #[test]
+    fn meter_report_without_meter() {
+        let report = |cumulative: &str| {
+            serde_json::from_str::<MeterReport>(&format!(
+                r#"{{"reportType": "production", "cumulative": {cumulative}}}"#
+            ))
+            .unwrap()
+        };
+
+        assert!(report(r#"{"currW": 1500.5, "rmsVoltage": 240.2}"#).is_metered());
+        assert!(report(r#"{"currW": 1500.5}"#).is_metered());
+        assert!(!report(r#"{"currW": 0.0, "rmsVoltage": 0.0}"#).is_metered());
+    }
+
+    #[test]
+    fn numbers_and_sizes() {
+        assert_eq!(parse_number(&serde_json::json!(230.5)), Some(230.5));
+        assert_eq!(parse_number(&serde_json::json!(" 230.5 ")), Some(230.5));
+        assert_eq!(parse_number(&serde_json::json!("n/a")), None);
+        assert_eq!(parse_number(&serde_json::json!(null)), None);
+
+        assert_eq!(
+            parse_size(&serde_json::json!("16 MB")),
+            Some(16.0 * 1024.0 * 1024.0)
+        );
+        assert_eq!(parse_size(&serde_json::json!("512")), Some(512.0));
+        assert_eq!(parse_size(&serde_json::json!(512)), Some(512.0));
+        assert_eq!(parse_size(&serde_json::json!("16 TB")), None);
+
+        assert_eq!(json_label(&serde_json::json!("D7.6.175")), "D7.6.175");
+        assert_eq!(json_label(&serde_json::json!(7)), "7");
+    }
+
+    #[test]
+    fn xml_tags() {
+        let body = "<envoy_info><device><sn>122233445566</sn><pn>800-00555-r03</pn>\
+                    <software> D7.6.175 </software></device></envoy_info>";
+
+        assert_eq!(xml_tag(body, "sn"), Some("122233445566"));
+        assert_eq!(xml_tag(body, "software"), Some("D7.6.175"));
+        assert_eq!(xml_tag(body, "imeter"), None);
+        assert_eq!(xml_tag("<sn>122233445566", "sn"), None);
+    }
+
+    #[test]
+    fn redact_identifying_keys() {
+        let mut value = serde_json::json!({
+            "serial_num": "122233445566",
+            "interfaces": [{"MAC-Addr": "00:1d:c0:00:00:00", "ip": null, "type": "ethernet"}],
+            "devices": [{"serialNumber": 122233445566u64, "producing": true}],
+            "timezone": "Europe/Amsterdam",
+        });
+
+        redact(&mut value);
+
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "serial_num": "REDACTED",
+                "interfaces": [{"MAC-Addr": "REDACTED", "ip": null, "type": "ethernet"}],
+                "devices": [{"serialNumber": "REDACTED", "producing": true}],
+                "timezone": "Europe/Amsterdam",
+            })
+        );
+    }
+
+    #[test]
+    fn inverter_labels_up_to_the_limit() {
+        let state = state(1);
+
+        let labels = state.inverter_labels("inverters", "1".to_string()).unwrap();
+        state
+            .inverter_production_watts
+            .get_or_create(&labels)
+            .set(250.0);
+
+        assert!(state
+            .inverter_labels("inverters", "2".to_string())
+            .is_none());
+        assert!(state
+            .inverter_labels("inverters", "1".to_string())
+            .is_some());
+
+        let output = encoded(&state);
+
+        assert!(
+            output.contains("enphase_envoy_inverter_production_watts{serial_num=\"1\"} 250.0\n")
+        );
+        assert!(
+            output.contains("enphase_envoy_dropped_inverters_total{collector=\"inverters\"} 1\n")
+        );
+    }
+
+    #[test]
+    fn inverter_names() {
+        let state = state(10);
+
+        for serial_num in ["1", "2"] {
+            let labels = state
+                .inverter_labels("inverters", serial_num.to_string())
+                .unwrap();
+            state
+                .inverter_production_watts
+                .get_or_create(&labels)
+                .set(250.0);
+        }
+
+        let output = encoded(&state);
+
+        assert!(
+            output.contains("enphase_envoy_inverter_production_watts{serial_num=\"1\"} 250.0\n")
+        );
+        assert!(output.contains(
+            "enphase_envoy_inverter_production_watts{serial_num=\"2\",name=\"roof\"} 250.0\n"
+        ));
+    }
+
+    #[test]
+    fn stale_inverters_are_removed() {
+        let state = state(10);
+
+        for serial_num in ["1", "2"] {
+            let labels = state
+                .inverter_labels("inverters", serial_num.to_string())
+                .unwrap();
+            state
+                .inverter_production_watts
+                .get_or_create(&labels)
+                .set(250.0);
+        }
+
+        let last_report = Instant::now() - Duration::from_secs(7200);
+        state
+            .inverter_last_seen
+            .lock()
+            .unwrap()
+            .insert("1".to_string(), last_report);
+
+        state.remove_stale_inverters(["2".to_string()].into_iter());
+
+        let output = encoded(&state);
+
+        assert!(!output.contains("serial_num=\"1\""));
+        assert!(output.contains("serial_num=\"2\""));
+        assert!(state.stale_inverters.lock().unwrap().contains("1"));
+
+        state.remove_stale_inverters(["1".to_string()].into_iter());
+
+        assert!(state.stale_inverters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn counters_never_go_down() {
+        let state = state(10);
+
+        state.set_counter("lifetime_watt_hours", &state.lifetime_watt_hours, 1000.0);
+        state.set_counter("lifetime_watt_hours", &state.lifetime_watt_hours, 900.0);
+
+        assert_eq!(state.lifetime_watt_hours.get(), 1000.0);
+
+        state.set_counter("lifetime_watt_hours", &state.lifetime_watt_hours, 1100.0);
+
+        assert_eq!(state.lifetime_watt_hours.get(), 1100.0);
+        assert!(encoded(&state).contains(
+            "enphase_envoy_counter_regressions_total{metric=\"enphase_envoy_lifetime_watt_hours\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn breaker_trips_after_threshold() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+        breaker.record(false);
+        assert!(!breaker.is_open());
+
+        breaker.record(false);
+        assert!(breaker.is_open());
+
+        breaker.record(true);
+        assert!(!breaker.is_open());
+
+        let disabled = CircuitBreaker::new(0, Duration::from_secs(60));
+
+        for _ in 0..10 {
+            disabled.record(false);
+        }
+
+        assert!(!disabled.is_open());
+    }
+}
@@ -0,0 +1,778 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::{error::ErrorKind, Arg, ArgAction, ArgMatches, Parser, Subcommand, ValueEnum};
+use serde_derive::Deserialize;
+
+const DEFAULT_PROMETHEUS_BIND_ADDR: &str = "[::1]:12345";
+
+const DEFAULT_METRICS_PREFIX: &str = "enphase_envoy";
+
+pub(crate) const DEFAULT_ENVOY_TIMEOUT_SECONDS: f64 = 5.0;
+
+const DEFAULT_ENLIGHTEN_URL: &str = "https://enlighten.enphaseenergy.com";
+const DEFAULT_ENTREZ_URL: &str = "https://entrez.enphaseenergy.com";
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
+pub(crate) struct Args {
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+
+    /// TOML file with flags as keys, flags on the command line take precedence.
+    #[arg(long = "config.file")]
+    config_file: Option<PathBuf>,
+
+    /// Prefix of all metric names, to match dashboards built for other exporters.
+    #[arg(long = "metrics.prefix", default_value = DEFAULT_METRICS_PREFIX, value_parser = metrics_prefix)]
+    pub(crate) metrics_prefix: String,
+
+    /// Label to add to all metrics as `name=value`, like `site=home`, can be repeated.
+    #[arg(long = "metrics.label", value_parser = metrics_label)]
+    pub(crate) metrics_labels: Vec<(String, String)>,
+
+    /// Address on which to expose metrics and web interface, or `unix:` and a socket path, can be repeated.
+    #[arg(long = "web.listen-address", default_value = DEFAULT_PROMETHEUS_BIND_ADDR)]
+    pub(crate) listen_addresses: Vec<String>,
+
+    /// Path under which to expose metrics.
+    #[arg(long = "web.telemetry-path", default_value = "/metrics", value_parser = telemetry_path)]
+    pub(crate) telemetry_path: String,
+
+    /// YAML file with TLS and basic auth settings for the web interface, like other exporters use.
+    #[arg(long = "web.config.file")]
+    pub(crate) web_config_file: Option<PathBuf>,
+
+    /// Enable reloading the config with `POST /-/reload`.
+    #[arg(long = "web.enable-lifecycle")]
+    pub(crate) web_enable_lifecycle: bool,
+
+    /// How long after the last successful scrape of the Envoy `/readyz` keeps reporting ready.
+    #[arg(long = "web.ready-max-age", default_value = "5m", value_parser = interval)]
+    pub(crate) ready_max_age: Duration,
+
+    /// When to request the Envoy, on every scrape or on a schedule in the background.
+    #[arg(long = "scrape.mode", value_enum, default_value_t = ScrapeMode::OnDemand)]
+    pub(crate) scrape_mode: ScrapeMode,
+
+    /// How often to request the Envoy in the background scrape mode, like `30s` or `1m`.
+    #[arg(long = "scrape.interval", default_value = "30s", value_parser = interval)]
+    pub(crate) scrape_interval: Duration,
+
+    #[command(flatten)]
+    pub(crate) envoy: EnvoyArgs,
+
+    /// Scheme for the Envoy address, unless it's a full URL.
+    #[arg(long = "envoy.scheme", value_enum, default_value_t = Scheme::Https)]
+    pub(crate) envoy_scheme: Scheme,
+
+    /// Timeout in seconds for connecting and for every request to the Envoy, Enlighten and Entrez.
+    #[arg(long = "envoy.timeout", default_value_t = DEFAULT_ENVOY_TIMEOUT_SECONDS)]
+    pub(crate) envoy_timeout: f64,
+
+    /// Number of retries for requests to the Envoy that failed to connect or got 5xx.
+    #[arg(long = "envoy.retries", default_value_t = 2)]
+    pub(crate) envoy_retries: u32,
+
+    /// Whether to check that the Envoy is reachable with the given credentials on startup.
+    #[arg(long = "envoy.startup-mode", value_enum, default_value_t = StartupMode::Lazy)]
+    pub(crate) envoy_startup_mode: StartupMode,
+
+    /// Stop making requests to the Envoy after this many failed scrapes in a row (0 to disable).
+    #[arg(long = "envoy.breaker-threshold", default_value_t = 3)]
+    pub(crate) envoy_breaker_threshold: u32,
+
+    /// Seconds to wait before trying the Envoy again after the breaker trips.
+    #[arg(long = "envoy.breaker-cooldown-seconds", default_value_t = 60)]
+    pub(crate) envoy_breaker_cooldown_seconds: u64,
+
+    /// Never contact Enlighten, requiring a token, a token file or digest auth.
+    #[arg(long = "envoy.local-only")]
+    pub(crate) envoy_local_only: bool,
+
+    #[command(flatten)]
+    pub(crate) enlighten: EnlightenArgs,
+
+    /// Renew the auth token this many seconds before it expires.
+    #[arg(long = "envoy.token-renew-before-seconds", default_value_t = 86400)]
+    pub(crate) envoy_token_renew_before_seconds: i64,
+
+    /// Remove inverters that the Envoy stopped reporting after this many seconds.
+    #[arg(long = "collector.inverters.stale-seconds", default_value_t = 3600)]
+    pub(crate) collector_inverters_stale_seconds: u64,
+
+    /// Maximum number of inverters to export series for, others are dropped.
+    #[arg(long = "collector.inverters.max", default_value_t = 1000)]
+    pub(crate) collector_inverters_max: usize,
+
+    /// TOML file mapping inverter serial numbers to names, added as the `name` label.
+    #[arg(long = "collector.inverters.names-file")]
+    pub(crate) collector_inverters_names_file: Option<PathBuf>,
+
+    /// Respond with 500 if any collector fails instead of serving partial metrics.
+    #[arg(long = "collector.fail-on-error")]
+    pub(crate) collector_fail_on_error: bool,
+}
+
+/// Collectors that can be turned on with `--collector.<name>` and off with
+/// `--no-collector.<name>`, whether they are enabled by default and what they collect.
+#[rustfmt::skip]
+pub(crate) const COLLECTORS: &[(&str, bool, &str)] = &[
+    ("production", true, "production from /ivp/meters/reports/production"),
+    ("inverters", true, "inverter production from /api/v1/production/inverters"),
+    ("production_json", true, "lifetime production from /production.json"),
+    ("consumption", true, "consumption from /ivp/meters/reports/consumption"),
+    ("ensemble_inventory", true, "batteries from /ivp/ensemble/inventory"),
+    ("ensemble_power", true, "battery power from /ivp/ensemble/power"),
+    ("production_summary", true, "production from /api/v1/production"),
+    ("inventory", true, "devices from /inventory.json"),
+    ("inverter_status", false, "inverter DC readings from /ivp/peb/devstatus (installer token)"),
+    ("home", true, "network and grid status from /home.json"),
+    ("events", true, "events from /datatab/event_dt.rb"),
+    ("tariff", true, "battery settings from /admin/lib/tariff"),
+    ("meters", true, "meter readings from /ivp/meters/readings"),
+    ("livedata", false, "live power flow from /ivp/livedata/status (new firmware)"),
+    ("dry_contacts", true, "dry contacts from /ivp/ensemble/dry_contacts"),
+    ("generator", true, "generator status from /ivp/ensemble/generator"),
+    ("grid_profile", false, "grid profile from /installer/agf/index.json (installer token)"),
+    ("export_limit", false, "export limit from /ivp/ss/pel_settings (installer token)"),
+    ("power_mode", true, "production power mode from /ivp/mod/<eid>/mode/power"),
+    ("inverter_lifetime", false, "inverter lifetime production from /ivp/pdm/device_data (installer token)"),
+    ("time", true, "the Envoy clock from /admin/lib/date_time_display.json"),
+    ("storm_guard", true, "Storm Guard status from /ivp/ss/stormguard"),
+    ("secctrl", true, "grid and battery control from /ivp/ensemble/secctrl"),
+    ("enpower_relays", true, "Enpower relays from /ivp/ensemble/relay"),
+];
+
+/// Adds flags for every collector, only showing the one that changes the default.
+pub(crate) fn collector_args(command: clap::Command) -> clap::Command {
+    COLLECTORS
+        .iter()
+        .fold(command, |command, (name, enabled, description)| {
+            let enable = format!("collector.{name}");
+            let disable = format!("no-collector.{name}");
+
+            command
+                .arg(
+                    Arg::new(enable.clone())
+                        .long(enable.clone())
+                        .help(format!("Enable collecting {description}"))
+                        .action(ArgAction::SetTrue)
+                        .overrides_with(disable.clone())
+                        .hide(*enabled),
+                )
+                .arg(
+                    Arg::new(disable.clone())
+                        .long(disable)
+                        .help(format!("Disable collecting {description}"))
+                        .action(ArgAction::SetTrue)
+                        .overrides_with(enable)
+                        .hide(!enabled),
+                )
+        })
+}
+
+/// Finds `--config.file` before the config is applied to the rest of the flags.
+fn config_file(command: &clap::Command, argv: &[OsString]) -> Option<PathBuf> {
+    command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(argv)
+        .ok()?
+        .get_one::<PathBuf>("config_file")
+        .cloned()
+}
+
+/// Config file turned into flags, along with Envoys that can't be set with flags.
+#[derive(Default)]
+struct Config {
+    args: Vec<OsString>,
+    envoys: Vec<EnvoyArgs>,
+}
+
+/// Keys other than `envoys` are flag names, checked against the command.
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    envoys: Vec<EnvoyArgs>,
+    #[serde(flatten)]
+    flags: toml::Table,
+}
+
+fn read_config(command: &clap::Command, path: &Path) -> Result<Config, clap::Error> {
+    let config = fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|config| toml::from_str::<ConfigFile>(&config).map_err(|err| err.to_string()))
+        .and_then(|config| {
+            Ok(Config {
+                args: config_args(command, "", config.flags)?,
+                envoys: envoys_config(config.envoys)?,
+            })
+        });
+
+    config.map_err(|err| {
+        command.clone().error(
+            ErrorKind::InvalidValue,
+            format!("error reading config file {}: {err}", path.display()),
+        )
+    })
+}
+
+/// Every Envoy needs the address, unless it's discovered, and the serial
+/// number to label metrics with.
+fn envoys_config(envoys: Vec<EnvoyArgs>) -> Result<Vec<EnvoyArgs>, String> {
+    for (i, envoy) in envoys.iter().enumerate() {
+        if envoy.envoy_address.is_some() == envoy.envoy_discover || envoy.envoy_serial.is_none() {
+            return Err(format!(
+                "envoy {} needs either address or discover, and serial",
+                i + 1
+            ));
+        }
+    }
+
+    Ok(envoys)
+}
+
+/// Keys in the config are flag names, either split into tables or quoted.
+/// Values from environment variables take precedence over the config.
+fn config_args(
+    command: &clap::Command,
+    prefix: &str,
+    config: toml::Table,
+) -> Result<Vec<OsString>, String> {
+    let mut args = vec![];
+
+    for (key, value) in config {
+        let name = if prefix.is_empty() {
+            key
+        } else {
+            format!("{prefix}.{key}")
+        };
+
+        if let toml::Value::Table(table) = value {
+            args.extend(config_args(command, &name, table)?);
+            continue;
+        }
+
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(name.as_str()))
+        else {
+            return Err(format!("unknown key {name:?}"));
+        };
+
+        if arg.get_env().is_some_and(|env| env::var_os(env).is_some()) {
+            continue;
+        }
+
+        let values = match value {
+            toml::Value::Boolean(enabled) if matches!(arg.get_action(), ArgAction::SetTrue) => {
+                let negated = format!("no-{name}");
+
+                if enabled {
+                    args.push(format!("--{name}").into());
+                } else if command
+                    .get_arguments()
+                    .any(|arg| arg.get_long() == Some(negated.as_str()))
+                {
+                    args.push(format!("--{negated}").into());
+                }
+
+                continue;
+            }
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+
+        for value in values {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                value => return Err(format!("unexpected value for {name:?}: {value:?}")),
+            };
+
+            args.push(format!("--{name}={value}").into());
+        }
+    }
+
+    Ok(args)
+}
+
+/// Addresses can be full URLs, otherwise the scheme is added.
+pub(crate) fn envoy_url(address: &str, scheme: Scheme) -> String {
+    let url = if address.contains("://") {
+        address.to_string()
+    } else {
+        let scheme = match scheme {
+            Scheme::Https => "https",
+            Scheme::Http => "http",
+        };
+
+        format!("{scheme}://{address}")
+    };
+
+    url.trim_end_matches('/').to_string()
+}
+
+fn telemetry_path(path: &str) -> Result<String, String> {
+    if !path.starts_with('/') {
+        return Err(format!("telemetry path {path:?} must start with /"));
+    }
+
+    Ok(path.to_string())
+}
+
+/// Label names follow the same rules as metric names, except for colons.
+/// Names starting with `__` are reserved for Prometheus.
+fn metrics_label(label: &str) -> Result<(String, String), String> {
+    let Some((name, value)) = label.split_once('=') else {
+        return Err(format!("label {label:?} must be in the name=value form"));
+    };
+
+    let valid = name
+        .chars()
+        .enumerate()
+        .all(|(i, c)| c.is_ascii_alphabetic() || c == '_' || (i > 0 && c.is_ascii_digit()));
+
+    if name.is_empty() || !valid || name.starts_with("__") || name == "envoy_serial" {
+        return Err(format!("invalid label name {name:?}"));
+    }
+
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Parses a number of seconds, optionally with a unit: `ms`, `s`, `m` or `h`.
+fn interval(interval: &str) -> Result<Duration, String> {
+    let split = interval
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(interval.len());
+
+    let (value, unit) = interval.split_at(split);
+
+    let multiplier = match unit {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("invalid unit in interval {interval:?}")),
+    };
+
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|value| Duration::try_from_secs_f64(value * multiplier).ok())
+        .filter(|interval| !interval.is_zero())
+        .ok_or_else(|| format!("invalid interval {interval:?}"))
+}
+
+/// Metric names are joined with `_`, so a trailing one is not needed.
+fn metrics_prefix(prefix: &str) -> Result<String, String> {
+    let prefix = prefix.trim_end_matches('_');
+
+    let valid = prefix.chars().enumerate().all(|(i, c)| {
+        c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit())
+    });
+
+    if prefix.is_empty() || !valid {
+        return Err(format!("invalid metric name prefix {prefix:?}"));
+    }
+
+    Ok(prefix.to_string())
+}
+
+/// Collectors that are enabled by default or with flags, the last flag wins.
+pub(crate) fn enabled_collectors(matches: &ArgMatches) -> HashSet<&'static str> {
+    COLLECTORS
+        .iter()
+        .filter(|(name, enabled, _)| {
+            if matches.get_flag(&format!("no-collector.{name}")) {
+                return false;
+            }
+
+            *enabled || matches.get_flag(&format!("collector.{name}"))
+        })
+        .map(|(name, _, _)| *name)
+        .collect()
+}
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Obtain a token from Enlighten, print it to stdout and its expiry to stderr.
+    Token(Box<TokenArgs>),
+    /// Probe every collector of the configured Envoys and print which ones work.
+    Check,
+    /// Print JSON responses of the Envoy to stdout, keyed by path.
+    Dump(DumpArgs),
+}
+
+#[derive(clap::Args)]
+pub(crate) struct DumpArgs {
+    /// Paths to request, like `/production.json`, all known endpoints by default.
+    pub(crate) paths: Vec<String>,
+
+    /// Replace serial numbers, addresses and other identifying values.
+    #[arg(long)]
+    pub(crate) redact: bool,
+}
+
+// Credentials are read the same way as for the exporter, flags other
+// than the serial number and credentials are ignored.
+#[derive(clap::Args)]
+pub(crate) struct TokenArgs {
+    #[command(flatten)]
+    pub(crate) envoy: EnvoyArgs,
+
+    #[command(flatten)]
+    pub(crate) enlighten: EnlightenArgs,
+}
+
+// Address and credentials of an Envoy, which can be set with flags
+// or for every entry of `envoys` in the config file.
+#[derive(clap::Args, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct EnvoyArgs {
+    /// Address of the Enphase Envoy on your local network, with an optional port or as a full URL.
+    #[arg(long = "envoy.address")]
+    #[serde(rename = "address")]
+    pub(crate) envoy_address: Option<String>,
+
+    /// Serial number of the Enphase Envoy (look up in the app).
+    #[arg(long = "envoy.serial")]
+    #[serde(rename = "serial")]
+    pub(crate) envoy_serial: Option<String>,
+
+    /// Find the address of the Envoy with mDNS, picking it by serial if there are several.
+    #[arg(long = "envoy.discover", conflicts_with = "envoy_address")]
+    #[serde(rename = "discover", default)]
+    pub(crate) envoy_discover: bool,
+
+    /// Enphase Envoy username (look up in the app).
+    #[arg(long = "envoy.username", env = "ENVOY_USERNAME")]
+    #[serde(rename = "username")]
+    pub(crate) envoy_username: Option<String>,
+
+    /// File with the Enphase Envoy username.
+    #[arg(long = "envoy.username-file", conflicts_with = "envoy_username")]
+    #[serde(rename = "username-file")]
+    pub(crate) envoy_username_file: Option<PathBuf>,
+
+    /// Enphase Envoy password.
+    #[arg(long = "envoy.password", env = "ENVOY_PASSWORD")]
+    #[serde(rename = "password")]
+    pub(crate) envoy_password: Option<String>,
+
+    /// File with the Enphase Envoy password.
+    #[arg(long = "envoy.password-file", conflicts_with = "envoy_password")]
+    #[serde(rename = "password-file")]
+    pub(crate) envoy_password_file: Option<PathBuf>,
+
+    /// Pre-generated Envoy auth token to use instead of Enlighten credentials.
+    #[arg(long = "envoy.token", env = "ENVOY_TOKEN")]
+    #[serde(rename = "token")]
+    pub(crate) envoy_token: Option<String>,
+
+    /// File with the Envoy auth token, re-read whenever it changes.
+    #[arg(long = "envoy.token-file", conflicts_with = "envoy_token")]
+    #[serde(rename = "token-file")]
+    pub(crate) envoy_token_file: Option<PathBuf>,
+
+    /// Local user for digest auth on firmware before 7 (`envoy` or `installer`).
+    #[arg(
+        long = "envoy.digest-username",
+        conflicts_with_all = ["envoy_token", "envoy_token_file"],
+        requires = "envoy_digest_password"
+    )]
+    #[serde(rename = "digest-username")]
+    pub(crate) envoy_digest_username: Option<String>,
+
+    /// Password of the local user for digest auth.
+    #[arg(long = "envoy.digest-password", env = "ENVOY_DIGEST_PASSWORD")]
+    #[serde(rename = "digest-password")]
+    pub(crate) envoy_digest_password: Option<String>,
+
+    /// CA certificate to verify the Envoy with instead of accepting any certificate.
+    #[arg(long = "envoy.ca-file")]
+    #[serde(rename = "ca-file")]
+    pub(crate) envoy_ca_file: Option<PathBuf>,
+
+    /// SHA-256 fingerprint of the Envoy certificate to accept instead of any certificate.
+    #[arg(long = "envoy.tls-fingerprint", conflicts_with = "envoy_ca_file")]
+    #[serde(rename = "tls-fingerprint")]
+    pub(crate) envoy_tls_fingerprint: Option<String>,
+
+    /// File to cache the Envoy auth token in between restarts.
+    #[arg(long = "envoy.token-cache-file")]
+    #[serde(rename = "token-cache-file")]
+    pub(crate) envoy_token_cache_file: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Clone)]
+pub(crate) struct EnlightenArgs {
+    /// Base URL of Enlighten, where the exporter logs in.
+    #[arg(long = "enlighten.url", default_value = DEFAULT_ENLIGHTEN_URL)]
+    pub(crate) enlighten_url: String,
+
+    /// Base URL of Entrez, which issues tokens for the Envoy.
+    #[arg(long = "entrez.url", default_value = DEFAULT_ENTREZ_URL)]
+    pub(crate) entrez_url: String,
+
+    /// Proxy for requests to Enlighten and Entrez, `HTTPS_PROXY` is used by default.
+    #[arg(long = "auth.proxy-url")]
+    pub(crate) proxy_url: Option<reqwest::Url>,
+
+    /// Required scope of tokens from Enlighten, installer tokens unlock installer-only endpoints.
+    #[arg(long = "envoy.token-scope", value_enum, default_value_t = TokenScope::Owner)]
+    pub(crate) token_scope: TokenScope,
+}
+
+/// Entrez issues tokens with the scope of the account, so the scope is
+/// checked in the `enphaseUser` claim of the token instead of being requested.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum TokenScope {
+    /// Any token works, installer tokens are a superset of owner ones.
+    Owner,
+    /// Only tokens of installer accounts are accepted.
+    Installer,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Scheme {
+    /// Firmware 7 and newer only serves HTTPS.
+    Https,
+    /// Older firmware and reverse proxies that terminate TLS.
+    Http,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum StartupMode {
+    /// Start serving right away and report problems with the Envoy on scrapes.
+    Lazy,
+    /// Authenticate and make a request to the Envoy, exiting if that fails.
+    FailFast,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum ScrapeMode {
+    /// Request the Envoy on every scrape, sharing the result between concurrent ones.
+    OnDemand,
+    /// Request the Envoy every `--scrape.interval` and serve the latest result.
+    Background,
+}
+
+/// Parses flags along with the config file, which is read again on reload.
+pub(crate) fn parse_args(
+    command: &clap::Command,
+    argv: &[OsString],
+) -> Result<(ArgMatches, Vec<EnvoyArgs>), clap::Error> {
+    let config = match config_file(command, argv) {
+        Some(path) => read_config(command, &path)?,
+        None => Config::default(),
+    };
+
+    // Flags from the config file go first, so that later ones override them.
+    let mut argv = argv.to_vec();
+    argv.splice(1..1, config.args);
+
+    let matches = command.clone().try_get_matches_from(argv)?;
+
+    Ok((matches, config.envoys))
+}
+
+/// Reads a secret from the given file or from systemd credentials
+/// passed with `LoadCredential=` under the given name.
+pub(crate) fn secret(path: Option<PathBuf>, credential: &str) -> Result<Option<String>, String> {
+    let path = path.or_else(|| {
+        let path = Path::new(&env::var_os("CREDENTIALS_DIRECTORY")?).join(credential);
+        path.exists().then_some(path)
+    });
+
+    path.as_deref().map(read_secret).transpose()
+}
+
+pub(crate) fn missing_argument(name: &str) -> String {
+    format!("the following required argument was not provided: {name}")
+}
+
+/// Inverter names are strings keyed by serial numbers, like `"202238096234" = "roof-east-3"`.
+pub(crate) fn read_inverter_names(path: &Path) -> Result<HashMap<String, String>, String> {
+    let names = fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|names| toml::from_str(&names).map_err(|err| err.to_string()));
+
+    names.map_err(|err| {
+        format!(
+            "error reading inverter names from {}: {err}",
+            path.display()
+        )
+    })
+}
+
+/// Reads a secret mounted as a file, ignoring the trailing newline.
+fn read_secret(path: &Path) -> Result<String, String> {
+    let secret = fs::read_to_string(path)
+        .map_err(|err| format!("error reading {}: {err}", path.display()))?;
+
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::CommandFactory;
+
+    #[test]
+    fn intervals() {
+        assert_eq!(interval("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(interval("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(interval("1.5m"), Ok(Duration::from_secs(90)));
+        assert_eq!(interval("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(interval("500ms"), Ok(Duration::from_millis(500)));
+
+        for invalid in ["", "s", "0", "-1m", "1d", "1 m", "1e400"] {
+            assert!(interval(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(
+            metrics_label("site=home"),
+            Ok(("site".to_string(), "home".to_string()))
+        );
+        assert_eq!(
+            metrics_label("query=a=b"),
+            Ok(("query".to_string(), "a=b".to_string()))
+        );
+        assert_eq!(
+            metrics_label("site="),
+            Ok(("site".to_string(), String::new()))
+        );
+
+        for invalid in [
+            "site",
+            "=home",
+            "1site=home",
+            "si-te=home",
+            "a:b=c",
+            "__name=home",
+            "envoy_serial=1",
+        ] {
+            assert!(metrics_label(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(
+            metrics_prefix("enphase_envoy"),
+            Ok("enphase_envoy".to_string())
+        );
+        assert_eq!(
+            metrics_prefix("solar:envoy_"),
+            Ok("solar:envoy".to_string())
+        );
+
+        for invalid in ["", "_", "1solar", "solar-envoy"] {
+            assert!(metrics_prefix(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn paths_and_urls() {
+        assert_eq!(telemetry_path("/metrics"), Ok("/metrics".to_string()));
+        assert!(telemetry_path("metrics").is_err());
+
+        assert_eq!(
+            envoy_url("envoy.local", Scheme::Https),
+            "https://envoy.local"
+        );
+        assert_eq!(
+            envoy_url("192.168.1.205", Scheme::Http),
+            "http://192.168.1.205"
+        );
+        assert_eq!(
+            envoy_url("http://192.168.1.205:8080/", Scheme::Https),
+            "http://192.168.1.205:8080"
+        );
+    }
+
+    #[test]
+    fn config_flags() {
+        let command = collector_args(Args::command());
+
+        let config = toml::from_str::<toml::Table>(
+            r#"
+            "web.enable-lifecycle" = true
+            "metrics.label" = ["site=home", "roof=south"]
+
+            [envoy]
+            retries = 5
+            timeout = 2.5
+
+            [collector]
+            production = false
+            livedata = true
+            inverters.max = 10
+            "#,
+        )
+        .unwrap();
+
+        let mut args = config_args(&command, "", config).unwrap();
+        args.sort();
+
+        assert_eq!(
+            args,
+            [
+                "--collector.inverters.max=10",
+                "--collector.livedata",
+                "--envoy.retries=5",
+                "--envoy.timeout=2.5",
+                "--metrics.label=roof=south",
+                "--metrics.label=site=home",
+                "--no-collector.production",
+                "--web.enable-lifecycle",
+            ]
+        );
+
+        let unknown = toml::from_str::<toml::Table>("envoy.adress = \"envoy.local\"").unwrap();
+        assert!(config_args(&command, "", unknown).is_err());
+
+        let unexpected = toml::from_str::<toml::Table>("envoy.retries = 1970-01-01").unwrap();
+        assert!(config_args(&command, "", unexpected).is_err());
+    }
+
+    #[test]
+    fn collectors() {
+        let command = collector_args(Args::command()).args_override_self(true);
+
+        let matches = command
+            .try_get_matches_from([
+                "enphase_envoy_exporter",
+                "--no-collector.production",
+                "--collector.livedata",
+                "--collector.events",
+                "--no-collector.events",
+                "--no-collector.inventory",
+                "--collector.inventory",
+            ])
+            .unwrap();
+
+        let collectors = enabled_collectors(&matches);
+
+        assert!(!collectors.contains("production"));
+        assert!(collectors.contains("livedata"));
+        assert!(!collectors.contains("events"));
+        assert!(collectors.contains("inventory"));
+        assert!(collectors.contains("inverters"));
+        assert!(!collectors.contains("inverter_status"));
+    }
+}
@@ -0,0 +1,414 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::FromArgMatches;
+use tokio::{
+    net::UdpSocket,
+    time::{sleep, timeout_at},
+};
+
+use crate::{
+    config::{parse_args, Args, EnvoyArgs},
+    error_summary, Exporter,
+};
+
+/// Service announced by Envoys over mDNS.
+const ENVOY_MDNS_SERVICE: &str = "_enphase-envoy._tcp.local";
+
+/// How long to wait for Envoys to answer a discovery query.
+const DISCOVERY_WAIT: Duration = Duration::from_secs(2);
+
+/// How often discovered Envoys are looked up again in case their address changed.
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Looks up discovered Envoys periodically and reloads once any of them
+/// moves to another address, which happens when DHCP leases change.
+pub(crate) async fn follow_discovered(exporter: Arc<Exporter>, mut addresses: Vec<IpAddr>) {
+    loop {
+        sleep(DISCOVERY_INTERVAL).await;
+
+        let discovered = async {
+            let (matches, mut envoys) =
+                parse_args(&exporter.command, &exporter.argv).map_err(error_summary)?;
+            let mut args = Args::from_arg_matches(&matches).map_err(error_summary)?;
+
+            discover(&mut args, &mut envoys).await
+        };
+
+        match discovered.await {
+            Ok(discovered) if discovered != addresses => {
+                eprintln!(
+                    "discovered envoys at {discovered:?} instead of {addresses:?}, reloading"
+                );
+
+                if let Err(err) = exporter.reload().await {
+                    eprintln!("error reloading config: {err}");
+                }
+
+                addresses = discovered;
+            }
+            Ok(_) => {}
+            Err(err) => eprintln!("error discovering envoys: {err}"),
+        }
+    }
+}
+
+/// Fills in the address and the serial number of Envoys with `--envoy.discover`
+/// from mDNS, returning the addresses that were found.
+pub(crate) async fn discover(
+    args: &mut Args,
+    envoys: &mut [EnvoyArgs],
+) -> Result<Vec<IpAddr>, String> {
+    let mut wanted = if envoys.is_empty() {
+        vec![&mut args.envoy]
+    } else {
+        envoys.iter_mut().collect()
+    };
+
+    wanted.retain(|envoy| envoy.envoy_discover);
+
+    if wanted.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let found = browse_envoys(DISCOVERY_WAIT)
+        .await
+        .map_err(|err| format!("error discovering envoys: {err}"))?;
+
+    let mut addresses = vec![];
+
+    for envoy in wanted {
+        if envoy.envoy_address.is_some() {
+            return Err("--envoy.discover can't be used with --envoy.address".into());
+        }
+
+        let matching = found
+            .iter()
+            .filter(|(serial_num, _)| {
+                envoy.envoy_serial.is_none() || *serial_num == envoy.envoy_serial
+            })
+            .collect::<Vec<_>>();
+
+        let (serial_num, address) = match matching[..] {
+            [found] => found.clone(),
+            [] => {
+                return Err(match &envoy.envoy_serial {
+                    Some(serial_num) => {
+                        format!("no envoy with serial {serial_num} found with mDNS")
+                    }
+                    None => "no envoy found with mDNS".into(),
+                })
+            }
+            _ => {
+                return Err(format!(
+                    "found {} envoys with mDNS, pick one with --envoy.serial",
+                    matching.len()
+                ))
+            }
+        };
+
+        envoy.envoy_address = Some(match address {
+            IpAddr::V4(address) => address.to_string(),
+            IpAddr::V6(address) => format!("[{address}]"),
+        });
+
+        envoy.envoy_serial = envoy.envoy_serial.take().or(serial_num);
+
+        addresses.push(address);
+    }
+
+    Ok(addresses)
+}
+
+/// Asks for the Envoy service over mDNS and returns serial numbers and
+/// addresses of all Envoys that answered. The query is sent from a random
+/// port, so that answers come back directly without joining the multicast
+/// group, which doesn't conflict with Avahi or other responders on the host.
+async fn browse_envoys(wait: Duration) -> Result<Vec<(Option<String>, IpAddr)>, std::io::Error> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let mut records = MdnsRecords::default();
+    let mut questions = vec![(dns_labels(ENVOY_MDNS_SERVICE), DNS_PTR)];
+
+    // Responders usually include service details with the answer, but
+    // missing ones are asked for once more.
+    for _ in 0..2 {
+        socket
+            .send_to(
+                &mdns_query(&questions),
+                (Ipv4Addr::new(224, 0, 0, 251), 5353),
+            )
+            .await?;
+
+        let deadline = tokio::time::Instant::now() + wait;
+        let mut buffer = [0; 9000];
+
+        while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+            let (length, _) = received?;
+            records.parse(&buffer[..length]);
+        }
+
+        questions = records.missing();
+        if questions.is_empty() {
+            break;
+        }
+    }
+
+    Ok(records.envoys())
+}
+
+const DNS_A: u16 = 1;
+const DNS_PTR: u16 = 12;
+const DNS_TXT: u16 = 16;
+const DNS_AAAA: u16 = 28;
+const DNS_SRV: u16 = 33;
+
+/// Names are kept as lowercase labels, as mDNS names are case-insensitive.
+type DnsName = Vec<String>;
+
+#[derive(Default)]
+struct MdnsRecords {
+    instances: Vec<DnsName>,
+    hosts: HashMap<DnsName, DnsName>,
+    txt: HashMap<DnsName, Vec<String>>,
+    addresses: HashMap<DnsName, Vec<IpAddr>>,
+}
+
+impl MdnsRecords {
+    /// Collects records from all sections of a response, ignoring malformed ones.
+    fn parse(&mut self, packet: &[u8]) -> Option<()> {
+        let header = packet.get(..12)?;
+        if header[2] & 0x80 == 0 {
+            return None;
+        }
+
+        let count = |index: usize| u16::from_be_bytes([header[index], header[index + 1]]) as usize;
+
+        let mut offset = 12;
+        for _ in 0..count(4) {
+            offset = dns_name(packet, offset)?.1 + 4;
+        }
+
+        for _ in 0..count(6) + count(8) + count(10) {
+            let (name, next) = dns_name(packet, offset)?;
+            let fixed = packet.get(next..next + 10)?;
+            let kind = u16::from_be_bytes([fixed[0], fixed[1]]);
+            let length = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+            let start = next + 10;
+            let data = packet.get(start..start + length)?;
+            offset = start + length;
+
+            match kind {
+                DNS_PTR if name == dns_labels(ENVOY_MDNS_SERVICE) => {
+                    let instance = dns_name(packet, start)?.0;
+                    if !self.instances.contains(&instance) {
+                        self.instances.push(instance);
+                    }
+                }
+                DNS_SRV if length > 6 => {
+                    self.hosts.insert(name, dns_name(packet, start + 6)?.0);
+                }
+                DNS_TXT => {
+                    let mut entries = vec![];
+                    let mut rest = data;
+                    while let Some((&size, tail)) = rest.split_first() {
+                        let entry = tail.get(..size as usize)?;
+                        entries.push(String::from_utf8_lossy(entry).to_string());
+                        rest = &tail[size as usize..];
+                    }
+                    self.txt.insert(name, entries);
+                }
+                DNS_A | DNS_AAAA => {
+                    let address = match (kind, data.len()) {
+                        (DNS_A, 4) => IpAddr::from(<[u8; 4]>::try_from(data).ok()?),
+                        (DNS_AAAA, 16) => IpAddr::from(<[u8; 16]>::try_from(data).ok()?),
+                        _ => return None,
+                    };
+                    let addresses = self.addresses.entry(name).or_default();
+                    if !addresses.contains(&address) {
+                        addresses.push(address);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Some(())
+    }
+
+    /// Questions for service details and addresses that weren't answered yet.
+    fn missing(&self) -> Vec<(DnsName, u16)> {
+        let mut questions = vec![];
+
+        for instance in &self.instances {
+            match self.hosts.get(instance) {
+                Some(host) if !self.addresses.contains_key(host) => {
+                    questions.push((host.clone(), DNS_A));
+                }
+                Some(_) => {}
+                None => questions.push((instance.clone(), DNS_SRV)),
+            }
+
+            if !self.txt.contains_key(instance) {
+                questions.push((instance.clone(), DNS_TXT));
+            }
+        }
+
+        questions
+    }
+
+    /// Serial numbers and addresses of Envoys, preferring IPv4 addresses.
+    fn envoys(&self) -> Vec<(Option<String>, IpAddr)> {
+        self.instances
+            .iter()
+            .filter_map(|instance| {
+                let addresses = self.addresses.get(self.hosts.get(instance)?)?;
+                let address = addresses
+                    .iter()
+                    .find(|address| address.is_ipv4())
+                    .or(addresses.first())?;
+
+                let serial_num = self.txt.get(instance).and_then(|entries| {
+                    entries
+                        .iter()
+                        .find_map(|entry| entry.strip_prefix("serialnum="))
+                        .map(str::to_string)
+                });
+
+                Some((serial_num, *address))
+            })
+            .collect()
+    }
+}
+
+fn dns_labels(name: &str) -> DnsName {
+    name.split('.').map(str::to_lowercase).collect()
+}
+
+/// Reads a possibly compressed name, returning it with the offset right after it.
+fn dns_name(packet: &[u8], mut offset: usize) -> Option<(DnsName, usize)> {
+    let mut labels = vec![];
+    let mut end = None;
+    // Every pointer has to go back before all labels read so far, which rules out loops.
+    let mut start = offset;
+
+    loop {
+        let length = *packet.get(offset)? as usize;
+
+        match length {
+            0 => return Some((labels, end.unwrap_or(offset + 1))),
+            length if length & 0xc0 == 0xc0 => {
+                let pointer = (length & 0x3f) << 8 | *packet.get(offset + 1)? as usize;
+                if pointer >= start {
+                    return None;
+                }
+
+                end.get_or_insert(offset + 2);
+                start = pointer;
+                offset = pointer;
+            }
+            // Other label types are obsolete.
+            length if length > 63 => return None,
+            length => {
+                let label = packet.get(offset + 1..offset + 1 + length)?;
+                labels.push(String::from_utf8_lossy(label).to_lowercase());
+                offset += 1 + length;
+            }
+        }
+    }
+}
+
+fn mdns_query(questions: &[(DnsName, u16)]) -> Vec<u8> {
+    let mut packet = vec![0; 12];
+    packet[4..6].copy_from_slice(&(questions.len() as u16).to_be_bytes());
+
+    for (name, kind) in questions {
+        for label in name {
+            packet.push(label.len() as u8);
+            packet.extend(label.as_bytes());
+        }
+
+        packet.push(0);
+        packet.extend(kind.to_be_bytes());
+        // Class IN with the bit asking for a unicast response.
+        packet.extend(0x8001u16.to_be_bytes());
+    }
+
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Response to the PTR query with everything needed in additional records,
+    /// compressed the way Envoys do it.
+    const ENVOY_MDNS_RESPONSE: [u8; 152] = [
+        0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x0e, 0x5f, 0x65,
+        0x6e, 0x70, 0x68, 0x61, 0x73, 0x65, 0x2d, 0x65, 0x6e, 0x76, 0x6f, 0x79, 0x04, 0x5f, 0x74,
+        0x63, 0x70, 0x05, 0x6c, 0x6f, 0x63, 0x61, 0x6c, 0x00, 0x00, 0x0c, 0x80, 0x01, 0x00, 0x00,
+        0x00, 0x78, 0x00, 0x08, 0x05, 0x45, 0x6e, 0x76, 0x6f, 0x79, 0xc0, 0x0c, 0xc0, 0x31, 0x00,
+        0x21, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x0e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x50,
+        0x05, 0x65, 0x6e, 0x76, 0x6f, 0x79, 0xc0, 0x20, 0xc0, 0x31, 0x00, 0x10, 0x80, 0x01, 0x00,
+        0x00, 0x00, 0x78, 0x00, 0x29, 0x11, 0x70, 0x72, 0x6f, 0x74, 0x6f, 0x76, 0x65, 0x72, 0x73,
+        0x3d, 0x37, 0x2e, 0x36, 0x2e, 0x31, 0x37, 0x35, 0x16, 0x73, 0x65, 0x72, 0x69, 0x61, 0x6c,
+        0x6e, 0x75, 0x6d, 0x3d, 0x31, 0x32, 0x32, 0x32, 0x33, 0x33, 0x34, 0x34, 0x35, 0x35, 0x36,
+        0x36, 0xc0, 0x4b, 0x00, 0x01, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x04, 0xc0, 0xa8,
+        0x01, 0xcd,
+    ];
+
+    #[test]
+    fn mdns_response() {
+        let mut records = MdnsRecords::default();
+        records.parse(&ENVOY_MDNS_RESPONSE).unwrap();
+
+        assert_eq!(
+            records.instances,
+            [dns_labels("envoy._enphase-envoy._tcp.local")]
+        );
+        assert!(records.missing().is_empty());
+        assert_eq!(
+            records.envoys(),
+            [(
+                Some("122233445566".to_string()),
+                IpAddr::from([192, 168, 1, 205])
+            )]
+        );
+    }
+
+    #[test]
+    fn mdns_truncated_response() {
+        for length in 0..ENVOY_MDNS_RESPONSE.len() {
+            let mut records = MdnsRecords::default();
+            assert!(records.parse(&ENVOY_MDNS_RESPONSE[..length]).is_none());
+        }
+    }
+
+    #[test]
+    fn mdns_query_is_not_a_response() {
+        let query = mdns_query(&[(dns_labels(ENVOY_MDNS_SERVICE), DNS_PTR)]);
+        assert!(MdnsRecords::default().parse(&query).is_none());
+    }
+
+    #[test]
+    fn dns_name_pointers() {
+        // `envoy` followed by a pointer to `local` at the start.
+        let packet = [
+            0x05, b'l', b'o', b'c', b'a', b'l', 0x00, 0x01, b'x', 0xc0, 0x00,
+        ];
+        assert_eq!(dns_name(&packet, 7), Some((dns_labels("x.local"), 11)));
+
+        // Pointers to itself, forwards, and back and forth between two names.
+        assert_eq!(dns_name(&[0xc0, 0x00], 0), None);
+        assert_eq!(dns_name(&[0xc0, 0x02, 0x00], 0), None);
+        assert_eq!(dns_name(&[0x01, b'a', 0xc0, 0x04, 0xc0, 0x00], 0), None);
+        assert_eq!(dns_name(&[0x00, 0x01, b'a', 0xc0, 0x01], 1), None);
+
+        // Pointer cut off at the end of the packet and obsolete label types.
+        assert_eq!(dns_name(&[0x00, 0xc0], 1), None);
+        assert_eq!(dns_name(&[0x40, 0x00], 0), None);
+    }
+}
@@ -1,368 +1,478 @@
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+mod auth;
+mod client;
+mod collectors;
+mod config;
+mod discovery;
+mod metrics;
+mod web;
+
+use std::{
+    borrow::Cow,
+    env,
+    ffi::OsString,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use axum::{
-    extract::State,
-    http::{HeaderMap, HeaderValue},
-    response::IntoResponse,
-    routing::get,
+    middleware,
+    routing::{get, post},
     Router,
 };
-use clap::Parser;
+use clap::{error::ErrorKind, ArgMatches, CommandFactory, FromArgMatches};
 use futures::future::join_all;
 use prometheus_client::{
-    encoding::{text::encode, EncodeLabelSet},
-    metrics::{counter::Counter, family::Family, gauge::Gauge},
+    metrics::{counter::Counter, info::Info},
     registry::Registry,
 };
-use reqwest::{multipart::Form, Error};
-use serde::de::DeserializeOwned;
-use serde_derive::{Deserialize, Serialize};
-use tokio::{net::TcpListener, spawn, sync::Mutex};
-
-const DEFAULT_PROMETHEUS_BIND_ADDR: &str = "[::1]:12345";
+use tokio::{
+    signal::unix::{signal, Signal, SignalKind},
+    spawn,
+    sync::Mutex,
+    task::JoinHandle,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    auth::{credentials, envoy_auth, print_token, Auth},
+    client::{envoy_tls, Client},
+    collectors::{check, dump, poll, AppState, BuildInfoLabels, CircuitBreaker, CollectorOptions},
+    config::{
+        collector_args, enabled_collectors, envoy_url, missing_argument, parse_args,
+        read_inverter_names, Args, Command, EnvoyArgs, ScrapeMode, StartupMode,
+    },
+    discovery::{discover, follow_discovered},
+    metrics::Envoys,
+    web::{
+        activated_listeners, basic_auth, healthz, metrics, notify_ready, read_web_config, readyz,
+        reload, serve_hyper, tls_server_config, BasicAuth, Listener, WebConfig,
+    },
+};
 
-const PROMETHEUS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let command = collector_args(Args::command()).args_override_self(true);
+    let argv = env::args_os().collect::<Vec<_>>();
 
-#[derive(Parser)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Address on which to expose metrics and web interface.
-    #[arg(long = "web.listen-address", default_value = DEFAULT_PROMETHEUS_BIND_ADDR)]
-    listen_address: String,
+    let (matches, mut envoys) = parse_args(&command, &argv).unwrap_or_else(|err| err.exit());
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
 
-    /// Address of the Enphase Envoy on your local network.
-    #[arg(long = "envoy.address")]
-    envoy_address: String,
+    let (check_only, dump_args) = match args.command.take() {
+        Some(Command::Token(args)) => {
+            let credentials = credentials(&args.envoy, &args.enlighten)
+                .unwrap_or_else(|err| Args::command().error(ErrorKind::InvalidValue, err).exit());
 
-    /// Serial number of the Enphase Envoy (look up in the app).
-    #[arg(long = "envoy.serial")]
-    envoy_serial: String,
+            return print_token(credentials).await;
+        }
+        Some(Command::Check) => (true, None),
+        Some(Command::Dump(args)) => (false, Some(args)),
+        None => (false, None),
+    };
 
-    /// Enphase Envoy username (look up in the app).
-    #[arg(long = "envoy.username", env = "ENVOY_USERNAME")]
-    envoy_username: String,
+    let discovered = discover(&mut args, &mut envoys)
+        .await
+        .unwrap_or_else(|err| Args::command().error(ErrorKind::InvalidValue, err).exit());
 
-    /// Enphase Envoy username.
-    #[arg(long = "envoy.password", env = "ENVOY_PASSWORD")]
-    envoy_password: String,
-}
+    let states = targets(&matches, &args, envoys, &[])
+        .unwrap_or_else(|err| Args::command().error(ErrorKind::InvalidValue, err).exit());
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    let args = Args::parse();
+    if check_only {
+        return check(states).await;
+    }
 
-    let client = Client::new(
-        &args.envoy_address,
-        &args.envoy_username,
-        &args.envoy_password,
-        &args.envoy_serial,
-    );
+    if let Some(dump_args) = dump_args {
+        return dump(states, dump_args).await;
+    }
 
-    eprintln!("listening on {}", &args.listen_address);
+    // SIGHUP terminates the process until the handler is installed,
+    // so it's done before the slow part of the startup.
+    let hangups = signal(SignalKind::hangup()).expect("error handling sighup");
 
-    let app = Router::new()
-        .route("/metrics", get(metrics))
-        .with_state(AppState::new(client));
+    // Sockets are taken while nothing else runs, as the environment
+    // variables that describe them are removed.
+    let activated = activated_listeners()
+        .unwrap_or_else(|err| panic!("error using sockets passed by systemd: {err}"));
 
-    let listener = TcpListener::bind(&args.listen_address)
-        .await
-        .expect("error binding to the listen address");
+    let web_config = match &args.web_config_file {
+        Some(path) => read_web_config(path),
+        None => Ok(WebConfig::default()),
+    }
+    .unwrap_or_else(|err| Args::command().error(ErrorKind::InvalidValue, err).exit());
+
+    let tls = web_config
+        .tls_server_config
+        .as_ref()
+        .map(tls_server_config)
+        .transpose()
+        .unwrap_or_else(|err| Args::command().error(ErrorKind::InvalidValue, err).exit());
+
+    if args.envoy_startup_mode == StartupMode::FailFast {
+        for (_, state) in &states {
+            if let Err(err) = state.client.check_connectivity().await {
+                eprintln!(
+                    "error connecting to the envoy at {}: {err}",
+                    state.client.base_url
+                );
+                std::process::exit(1);
+            }
+        }
+    }
 
-    axum::serve(listener, app)
-        .await
-        .expect("error running server");
-}
+    let mut targets = vec![];
+    for (_, state) in states {
+        targets.push(Target::start(state, &args).await);
+    }
 
-#[derive(Clone)]
-struct AppState {
-    client: Client,
-    registry: Arc<Registry>,
-    production_watts: Gauge<f64, AtomicU64>,
-    inverter_production_watts: Family<InverterLabels, Gauge<f64, AtomicU64>>,
-    lifetime_watt_hours: Counter<f64, AtomicU64>,
-}
+    let encoding_errors = Counter::default();
+    let registry = exporter_registry(&args, &targets, &encoding_errors);
+
+    let exporter = Arc::new(Exporter {
+        command,
+        argv,
+        targets: std::sync::RwLock::new(Arc::new(targets)),
+        registry: std::sync::RwLock::new(Arc::new(registry)),
+        encoding_errors,
+        reloading: Mutex::default(),
+    });
+
+    spawn(reload_on_sighup(exporter.clone(), hangups));
+    if !discovered.is_empty() {
+        spawn(follow_discovered(exporter.clone(), discovered));
+    }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
-struct InverterLabels {
-    serial_num: String,
-}
+    let mut app = Router::new()
+        .route(&args.telemetry_path, get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz));
 
-impl AppState {
-    fn new(client: Client) -> Self {
-        let mut registry = Registry::default();
+    if args.web_enable_lifecycle {
+        app = app.route("/-/reload", post(reload));
+    }
 
-        let production_watts = Gauge::<f64, AtomicU64>::default();
+    let mut app = app.with_state(exporter.clone());
 
-        registry.register(
-            "enphase_envoy_production_watts",
-            "Currently produced watts",
-            production_watts.clone(),
-        );
+    if !web_config.basic_auth_users.is_empty() {
+        let auth = BasicAuth {
+            users: Arc::new(web_config.basic_auth_users),
+            verified: Arc::default(),
+        };
 
-        let inverter_production_watts = Family::<InverterLabels, Gauge<f64, AtomicU64>>::default();
+        app = app.layer(middleware::from_fn_with_state(auth, basic_auth));
+    }
 
-        registry.register(
-            "enphase_envoy_inverter_production_watts",
-            "Last known production for inverters",
-            inverter_production_watts.clone(),
-        );
+    let tls = tls.map(|tls| TlsAcceptor::from(Arc::new(tls)));
 
-        let lifetime_watt_hours = Counter::<f64, AtomicU64>::default();
+    let mut listeners = activated;
 
-        registry.register(
-            "enphase_envoy_lifetime_watt_hours",
-            "Total amount of watt hours produced by the system",
-            lifetime_watt_hours.clone(),
-        );
+    if listeners.is_empty() {
+        for address in &args.listen_addresses {
+            let listener = Listener::bind(address)
+                .await
+                .unwrap_or_else(|err| panic!("error binding to {address}: {err}"));
 
-        let registry = Arc::new(registry);
+            eprintln!("listening on {address}");
 
-        Self {
-            client,
-            registry,
-            production_watts,
-            inverter_production_watts,
-            lifetime_watt_hours,
+            listeners.push(listener);
         }
+    } else {
+        eprintln!("listening on {} sockets passed by systemd", listeners.len());
     }
-}
 
-async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
-    let mut updates = vec![];
+    if env::var_os("NOTIFY_SOCKET").is_some() {
+        spawn(notify_ready(exporter));
+    }
 
-    updates.push(spawn({
-        let client = state.client.clone();
-        async move {
-            state.production_watts.set(
-                client
-                    .production_watts()
-                    .await
-                    .expect("error getting production value"),
-            );
-        }
-    }));
+    join_all(listeners.into_iter().map(|listener| {
+        let app = app.clone();
+        let tls = tls.clone();
 
-    updates.push(spawn({
-        let client = state.client.clone();
         async move {
-            let inverter_production = client
-                .inverter_production_watts()
-                .await
-                .expect("error getting inverter production");
-
-            for inverter in inverter_production {
-                let serial_num = inverter.serial_num;
-                state
-                    .inverter_production_watts
-                    .get_or_create(&InverterLabels { serial_num })
-                    .set(inverter.last_known_watts);
+            match (listener, tls) {
+                (Listener::Tcp(listener), None) => axum::serve(listener, app)
+                    .await
+                    .expect("error running server"),
+                (listener, tls) => serve_hyper(listener, app, tls).await,
             }
         }
-    }));
+    }))
+    .await;
+}
 
-    updates.push(spawn({
-        let client = state.client.clone();
-        async move {
-            state.lifetime_watt_hours.inner().store(
-                client
-                    .lifetime_watt_hours()
-                    .await
-                    .expect("error getting lifetime production value")
-                    .to_bits(),
-                Ordering::Relaxed,
-            );
+/// Builds the state of every Envoy. Envoys that were already monitored
+/// keep their metrics, so that counters don't reset on reload.
+fn targets(
+    matches: &ArgMatches,
+    args: &Args,
+    envoys: Vec<EnvoyArgs>,
+    previous: &[Target],
+) -> Result<Vec<(Option<String>, AppState)>, String> {
+    // Metrics of Envoys from the config file are labelled with their
+    // serial numbers, so that they don't collide with each other.
+    let envoys = if envoys.is_empty() {
+        vec![(args.envoy.clone(), None)]
+    } else {
+        if args.envoy.envoy_address.is_some() {
+            return Err("--envoy.address can't be used with envoys in the config file".into());
         }
-    }));
 
-    join_all(updates).await;
+        envoys
+            .into_iter()
+            .map(|envoy| {
+                let serial_num = envoy.envoy_serial.clone();
+                (envoy, serial_num)
+            })
+            .collect()
+    };
+
+    let options = CollectorOptions {
+        collectors: enabled_collectors(matches),
+        inverter_stale_after: Duration::from_secs(args.collector_inverters_stale_seconds),
+        max_inverters: args.collector_inverters_max,
+        inverter_names: match &args.collector_inverters_names_file {
+            Some(path) => Arc::new(read_inverter_names(path)?),
+            None => Arc::default(),
+        },
+        fail_on_error: args.collector_fail_on_error,
+        prefix: args.metrics_prefix.clone(),
+    };
+
+    let mut states = vec![];
+
+    for (envoy, serial_num) in envoys {
+        let envoy_address = envoy
+            .envoy_address
+            .clone()
+            .ok_or_else(|| missing_argument("--envoy.address"))?;
+
+        let client = Client::new(
+            envoy_url(&envoy_address, args.envoy_scheme),
+            envoy_auth(&envoy, &args.enlighten, args.envoy_local_only)?,
+            envoy_tls(&envoy)?,
+            envoy.envoy_token_cache_file,
+            Duration::from_secs_f64(args.envoy_timeout),
+            args.envoy_retries,
+        )?;
+
+        let breaker = CircuitBreaker::new(
+            args.envoy_breaker_threshold,
+            Duration::from_secs(args.envoy_breaker_cooldown_seconds),
+        );
 
-    let mut buffer = String::new();
-    encode(&mut buffer, &state.registry).expect("error encoding prometheus data");
+        let labels = serial_num
+            .iter()
+            .map(|serial_num| ("envoy_serial".to_string(), serial_num.clone()))
+            .chain(args.metrics_labels.iter().cloned())
+            .collect::<Vec<_>>();
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "content-type",
-        HeaderValue::from_static(PROMETHEUS_CONTENT_TYPE),
-    );
+        let previous = previous
+            .iter()
+            .find(|target| target.state.labels == labels && target.state.prefix == options.prefix);
 
-    (headers, buffer)
+        let state = match previous {
+            Some(target) => target.state.reconfigure(client, &options, breaker),
+            None => AppState::new(client, labels, &options, breaker),
+        };
+
+        states.push((serial_num, state));
+    }
+
+    Ok(states)
 }
 
-/// Ideally we'd use [enphase](https://docs.rs/enphase/) crate, but it relies
-/// on valid TLS certificates, while Enphase self-signs theirs for Envoy.
-#[derive(Clone)]
-struct Client {
-    hostname: String,
-    username: String,
-    password: String,
-    serial_num: String,
-    client: reqwest::Client,
-    token: Arc<Mutex<Option<String>>>,
+/// Metrics of all Envoys, followed by metrics of the exporter itself with
+/// the labels of every Envoy, except for the serial number.
+fn exporter_registry(args: &Args, targets: &[Target], encoding_errors: &Counter) -> Registry {
+    let mut registry = Registry::with_prefix(&args.metrics_prefix);
+
+    registry.register_collector(Box::new(Envoys(
+        targets
+            .iter()
+            .map(|target| target.state.registry.clone())
+            .collect(),
+    )));
+
+    let exporter = registry.sub_registry_with_labels(
+        args.metrics_labels
+            .iter()
+            .map(|(name, value)| (Cow::Owned(name.clone()), Cow::Owned(value.clone()))),
+    );
+
+    exporter.register(
+        "exporter_build",
+        "Build information about the exporter",
+        Info::new(BuildInfoLabels {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            revision: env!("BUILD_REVISION").to_string(),
+            rustc_version: env!("BUILD_RUSTC_VERSION").to_string(),
+        }),
+    );
+
+    exporter.register(
+        "encoding_errors",
+        "Number of scrapes that failed because metrics couldn't be encoded",
+        encoding_errors.clone(),
+    );
+
+    registry
 }
 
-impl Client {
-    fn new(
-        hostname: impl AsRef<str>,
-        username: impl AsRef<str>,
-        password: impl AsRef<str>,
-        serial_num: impl AsRef<str>,
-    ) -> Self {
-        let hostname = hostname.as_ref().into();
-        let username = username.as_ref().into();
-        let password = password.as_ref().into();
-        let serial_num = serial_num.as_ref().into();
-
-        let client = reqwest::ClientBuilder::new()
-            .danger_accept_invalid_certs(true)
-            .build()
-            .expect("error building reqwest client");
-
-        let token = Arc::new(Mutex::new(None));
+/// Targets being scraped, swapped as a whole when the config is reloaded.
+struct Exporter {
+    command: clap::Command,
+    argv: Vec<OsString>,
+    targets: std::sync::RwLock<Arc<Vec<Target>>>,
+    /// Metrics of the current targets and of the exporter itself.
+    registry: std::sync::RwLock<Arc<Registry>>,
+    /// Kept across reloads, unlike the registry.
+    encoding_errors: Counter,
+    /// Reloads run one at a time, so that none of them is lost.
+    reloading: Mutex<()>,
+}
 
-        Self {
-            hostname,
-            username,
-            password,
-            serial_num,
-            client,
-            token,
-        }
+impl Exporter {
+    fn targets(&self) -> Arc<Vec<Target>> {
+        self.targets.read().expect("targets lock poisoned").clone()
     }
 
-    async fn authenticate(&self) -> Result<String, Error> {
-        let form = Form::new()
-            .text("user[email]", self.username.clone())
-            .text("user[password]", self.password.clone());
-
-        let response = self
-            .client
-            .post("https://enlighten.enphaseenergy.com/login/login.json")
-            .multipart(form)
-            .send()
-            .await?
-            .error_for_status()?;
-
-        let session_id = response.json::<LoginResponse>().await?.session_id;
-        let username = self.username.clone();
-        let serial_num = self.serial_num.clone();
-
-        let response = self
-            .client
-            .post("https://entrez.enphaseenergy.com/tokens")
-            .json(&TokenRequest {
-                session_id,
-                username,
-                serial_num,
-            })
-            .send()
-            .await?
-            .error_for_status()?;
-
-        response
-            .bytes()
-            .await
-            .map(|bytes| String::from_utf8_lossy(&bytes).to_string())
+    fn registry(&self) -> Arc<Registry> {
+        self.registry
+            .read()
+            .expect("registry lock poisoned")
+            .clone()
     }
 
-    async fn token(&self) -> Result<String, Error> {
-        let mut guard = self.token.lock().await;
-        match &*guard {
-            Some(token) => Ok(token.clone()),
-            None => {
-                let token = self.authenticate().await?;
-                guard.replace(token.clone());
-                Ok(token)
-            }
+    /// Reads the flags and the config file again, keeping the current
+    /// targets if anything is wrong. The listen address and the telemetry
+    /// path can only be changed with a restart.
+    async fn reload(&self) -> Result<(), String> {
+        let _reloading = self.reloading.lock().await;
+
+        let (matches, mut envoys) = parse_args(&self.command, &self.argv).map_err(error_summary)?;
+        let mut args = Args::from_arg_matches(&matches).map_err(error_summary)?;
+
+        discover(&mut args, &mut envoys).await?;
+
+        let states = targets(&matches, &args, envoys, &self.targets())?;
+
+        let mut targets = vec![];
+        for (_, state) in states {
+            targets.push(Target::start(state, &args).await);
         }
-    }
 
-    async fn production_watts(&self) -> Result<f64, Error> {
-        self.get::<ProductionResponse>("/ivp/meters/reports/production")
-            .await
-            .map(|response| response.cumulative.current_watts)
-    }
+        eprintln!("reloaded config with {} envoys", targets.len());
 
-    async fn inverter_production_watts(&self) -> Result<Vec<InverterProduction>, Error> {
-        self.get::<Vec<InverterProduction>>("/api/v1/production/inverters")
-            .await
-    }
+        let registry = exporter_registry(&args, &targets, &self.encoding_errors);
 
-    async fn lifetime_watt_hours(&self) -> Result<f64, Error> {
-        self.get::<CumulativeProductionResponse>("/production.json")
-            .await
-            .map(|response| {
-                response
-                    .production
-                    .into_iter()
-                    .find(|item| item.kind == "inverters")
-                    .map(|item| item.lifetime_watt_hours)
-                    .unwrap_or_default()
-            })
-    }
+        *self.targets.write().expect("targets lock poisoned") = Arc::new(targets);
+        *self.registry.write().expect("registry lock poisoned") = Arc::new(registry);
 
-    async fn get<R>(&self, path: &str) -> Result<R, Error>
-    where
-        R: DeserializeOwned,
-    {
-        let token = self.token().await?;
-
-        let response = self
-            .client
-            .get(format!("https://{}{}", self.hostname, path,))
-            .bearer_auth(token)
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<R>()
-            .await?;
-
-        Ok(response)
+        Ok(())
     }
 }
 
-#[derive(Deserialize, Debug)]
-struct LoginResponse {
-    session_id: String,
+/// An Envoy along with the tasks renewing its token and polling it, which
+/// stop once the Envoy is replaced on reload and no scrape is using it anymore.
+struct Target {
+    state: AppState,
+    token_renewal: JoinHandle<()>,
+    /// Only set in the background scrape mode.
+    poller: Option<JoinHandle<()>>,
+    ready_max_age: Duration,
 }
 
-#[derive(Serialize, Debug)]
-struct TokenRequest {
-    session_id: String,
-    username: String,
-    serial_num: String,
-}
+impl Target {
+    async fn start(state: AppState, args: &Args) -> Self {
+        let token_renewal = spawn(
+            state
+                .client
+                .clone()
+                .maintain_token(args.envoy_token_renew_before_seconds),
+        );
 
-#[derive(Deserialize, Debug)]
-struct ProductionResponse {
-    cumulative: CumulativeProduction,
+        match state.client.info().await {
+            Ok(info) => {
+                state.info.get_or_create(&info.labels).set(1);
+            }
+            Err(err) => eprintln!(
+                "error getting envoy info from {}: {err}",
+                state.client.base_url
+            ),
+        }
+
+        let poller = match args.scrape_mode {
+            ScrapeMode::OnDemand => None,
+            ScrapeMode::Background => Some(spawn(poll(state.clone(), args.scrape_interval))),
+        };
+
+        Self {
+            state,
+            token_renewal,
+            poller,
+            ready_max_age: args.ready_max_age,
+        }
+    }
+
+    /// Returns why the Envoy is not ready, if it isn't. Only the state left
+    /// by scrapes is checked, so that probes never wait for the Envoy.
+    fn not_ready(&self) -> Option<String> {
+        let client = &self.state.client;
+
+        if !matches!(client.auth, Auth::Digest { .. }) && client.token().is_err() {
+            return Some(format!("no token for the envoy at {}", client.base_url));
+        }
+
+        if let Some(expiry) = client.token_expiry() {
+            if expiry <= unix_time() as i64 {
+                return Some(format!(
+                    "token for the envoy at {} expired",
+                    client.base_url
+                ));
+            }
+        }
+
+        if !self.state.succeeded_within(self.ready_max_age) {
+            return Some(format!(
+                "no successful scrape of the envoy at {} in the last {:?}",
+                client.base_url, self.ready_max_age
+            ));
+        }
+
+        None
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct CumulativeProduction {
-    #[serde(rename = "currW")]
-    current_watts: f64,
+impl Drop for Target {
+    fn drop(&mut self) {
+        self.token_renewal.abort();
+
+        if let Some(poller) = &self.poller {
+            poller.abort();
+        }
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct InverterProduction {
-    #[serde(rename = "serialNumber")]
-    serial_num: String,
-    #[serde(rename = "lastReportWatts")]
-    last_known_watts: f64,
+/// Signals that arrive during a reload are handled by a single reload after it.
+async fn reload_on_sighup(exporter: Arc<Exporter>, mut hangups: Signal) {
+    while hangups.recv().await.is_some() {
+        if let Err(err) = exporter.reload().await {
+            eprintln!("error reloading config: {err}");
+        }
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct CumulativeProductionResponse {
-    production: Vec<CumulativeProductionResponseItem>,
+/// The first line of a clap error, without the usage that follows it.
+fn error_summary(err: clap::Error) -> String {
+    err.to_string()
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches("error: ")
+        .to_string()
 }
 
-#[derive(Deserialize, Debug)]
-struct CumulativeProductionResponseItem {
-    #[serde(rename = "type")]
-    kind: String,
-    #[serde(rename = "whLifetime")]
-    lifetime_watt_hours: f64,
+fn unix_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is before unix epoch")
+        .as_secs_f64()
 }
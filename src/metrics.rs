@@ -0,0 +1,313 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    hash::Hash,
+    sync::{Arc, RwLock},
+};
+
+use prometheus_client::{
+    collector::Collector,
+    encoding::{
+        DescriptorEncoder, EncodeCounterValue, EncodeGaugeValue, EncodeLabel, EncodeLabelSet,
+        EncodeMetric, LabelSetEncoder, MetricEncoder,
+    },
+    metrics::{counter, counter::Counter, gauge, gauge::Gauge, MetricType, TypedMetric},
+};
+
+/// Metrics of a single Envoy. They are not encoded on their own, but by
+/// [`Envoys`], so that every family is described once for all Envoys.
+#[derive(Debug)]
+pub(crate) struct EnvoyRegistry {
+    /// Labels of every series of the Envoy.
+    labels: Vec<(String, String)>,
+    metrics: Vec<(String, String, Box<dyn EnvoyMetric>)>,
+}
+
+impl EnvoyRegistry {
+    pub(crate) fn new(labels: Vec<(String, String)>) -> Self {
+        Self {
+            labels,
+            metrics: vec![],
+        }
+    }
+
+    /// Same as [`prometheus_client::registry::Registry::register`].
+    pub(crate) fn register(
+        &mut self,
+        name: impl Into<String>,
+        help: impl Into<String>,
+        metric: impl EnvoyMetric,
+    ) {
+        self.metrics
+            .push((name.into(), help.into() + ".", Box::new(metric)));
+    }
+}
+
+/// Metrics of all Envoys, interleaved by family. Every Envoy registers the
+/// same metrics in the same order, so families are matched by position.
+#[derive(Debug)]
+pub(crate) struct Envoys(pub(crate) Vec<Arc<EnvoyRegistry>>);
+
+impl Collector for Envoys {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        let Some(first) = self.0.first() else {
+            return Ok(());
+        };
+
+        for (index, (name, help, metric)) in first.metrics.iter().enumerate() {
+            let mut encoder = encoder.encode_descriptor(name, help, None, metric.metric_type())?;
+
+            for envoy in &self.0 {
+                envoy.metrics[index].2.encode(&envoy.labels, &mut encoder)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A metric of a single Envoy, which can be encoded along with the same
+/// metric of other Envoys under a single descriptor.
+pub(crate) trait EnvoyMetric: Debug + Send + Sync + 'static {
+    fn metric_type(&self) -> MetricType;
+
+    fn encode(
+        &self,
+        labels: &[(String, String)],
+        encoder: &mut MetricEncoder,
+    ) -> Result<(), std::fmt::Error>;
+}
+
+impl<N, A> EnvoyMetric for Gauge<N, A>
+where
+    N: EncodeGaugeValue + Debug + Send + Sync + 'static,
+    A: gauge::Atomic<N> + Debug + Send + Sync + 'static,
+{
+    fn metric_type(&self) -> MetricType {
+        Self::TYPE
+    }
+
+    fn encode(
+        &self,
+        labels: &[(String, String)],
+        encoder: &mut MetricEncoder,
+    ) -> Result<(), std::fmt::Error> {
+        // Empty labels would still be encoded as `{}`.
+        if labels.is_empty() {
+            return encoder.encode_gauge(&self.get());
+        }
+
+        encoder
+            .encode_family(&SeriesLabels {
+                envoy: labels,
+                series: &(),
+            })?
+            .encode_gauge(&self.get())
+    }
+}
+
+impl<N, A> EnvoyMetric for Counter<N, A>
+where
+    N: EncodeCounterValue + Debug + Send + Sync + 'static,
+    A: counter::Atomic<N> + Debug + Send + Sync + 'static,
+{
+    fn metric_type(&self) -> MetricType {
+        Self::TYPE
+    }
+
+    fn encode(
+        &self,
+        labels: &[(String, String)],
+        encoder: &mut MetricEncoder,
+    ) -> Result<(), std::fmt::Error> {
+        if labels.is_empty() {
+            return encoder.encode_counter::<(), _, u64>(&self.get(), None);
+        }
+
+        encoder
+            .encode_family(&SeriesLabels {
+                envoy: labels,
+                series: &(),
+            })?
+            .encode_counter::<(), _, u64>(&self.get(), None)
+    }
+}
+
+/// Same as [`prometheus_client::metrics::family::Family`], except that the
+/// labels of the Envoy are added to every series when it's encoded, which
+/// the client library only supports for whole registries.
+#[derive(Debug)]
+pub(crate) struct Family<S, M, C = fn() -> M> {
+    metrics: Arc<RwLock<HashMap<S, M>>>,
+    constructor: C,
+}
+
+impl<S: Clone + Eq + Hash, M: Clone, C: Fn() -> M> Family<S, M, C> {
+    pub(crate) fn new_with_constructor(constructor: C) -> Self {
+        Self {
+            metrics: Arc::default(),
+            constructor,
+        }
+    }
+
+    pub(crate) fn get_or_create(&self, labels: &S) -> M {
+        if let Some(metric) = self
+            .metrics
+            .read()
+            .expect("metrics lock poisoned")
+            .get(labels)
+        {
+            return metric.clone();
+        }
+
+        self.metrics
+            .write()
+            .expect("metrics lock poisoned")
+            .entry(labels.clone())
+            .or_insert_with(&self.constructor)
+            .clone()
+    }
+
+    pub(crate) fn remove(&self, labels: &S) -> bool {
+        self.metrics
+            .write()
+            .expect("metrics lock poisoned")
+            .remove(labels)
+            .is_some()
+    }
+
+    pub(crate) fn clear(&self) {
+        self.metrics.write().expect("metrics lock poisoned").clear();
+    }
+}
+
+impl<S, M: Default> Default for Family<S, M> {
+    fn default() -> Self {
+        Self {
+            metrics: Arc::default(),
+            constructor: M::default,
+        }
+    }
+}
+
+impl<S, M, C: Clone> Clone for Family<S, M, C> {
+    fn clone(&self) -> Self {
+        Self {
+            metrics: self.metrics.clone(),
+            constructor: self.constructor.clone(),
+        }
+    }
+}
+
+impl<S, M, C> EnvoyMetric for Family<S, M, C>
+where
+    S: EncodeLabelSet + Debug + Send + Sync + 'static,
+    M: EncodeMetric + TypedMetric + Debug + Send + Sync + 'static,
+    C: Debug + Send + Sync + 'static,
+{
+    fn metric_type(&self) -> MetricType {
+        M::TYPE
+    }
+
+    fn encode(
+        &self,
+        labels: &[(String, String)],
+        encoder: &mut MetricEncoder,
+    ) -> Result<(), std::fmt::Error> {
+        for (series, metric) in self.metrics.read().expect("metrics lock poisoned").iter() {
+            metric.encode(encoder.encode_family(&SeriesLabels {
+                envoy: labels,
+                series,
+            })?)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Labels of the Envoy followed by the labels of the series.
+struct SeriesLabels<'a, S> {
+    envoy: &'a [(String, String)],
+    series: &'a S,
+}
+
+impl<S: EncodeLabelSet> EncodeLabelSet for SeriesLabels<'_, S> {
+    fn encode(&self, mut encoder: LabelSetEncoder) -> Result<(), std::fmt::Error> {
+        for (name, value) in self.envoy {
+            (name.as_str(), value.as_str()).encode(encoder.encode_label())?;
+        }
+
+        self.series.encode(encoder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use prometheus_client::{encoding::text::encode, registry::Registry};
+
+    fn envoy(labels: &[(&str, &str)], watts: i64) -> Arc<EnvoyRegistry> {
+        let mut registry = EnvoyRegistry::new(
+            labels
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        );
+
+        let up = Gauge::<i64>::default();
+        up.set(1);
+
+        registry.register("up", "Whether the Envoy was reachable", up);
+
+        let phase_watts = Family::<Vec<(String, String)>, Gauge>::default();
+        phase_watts
+            .get_or_create(&vec![("phase".to_string(), "ph-a".to_string())])
+            .set(watts);
+
+        registry.register("phase_watts", "Current production", phase_watts);
+
+        Arc::new(registry)
+    }
+
+    fn encoded(envoys: Vec<Arc<EnvoyRegistry>>) -> String {
+        let mut registry = Registry::with_prefix("enphase_envoy");
+        registry.register_collector(Box::new(Envoys(envoys)));
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn families_of_several_envoys() {
+        assert_eq!(
+            encoded(vec![
+                envoy(&[("envoy_serial", "1")], 1500),
+                envoy(&[("envoy_serial", "2")], 700),
+            ]),
+            "# HELP enphase_envoy_up Whether the Envoy was reachable.\n\
+             # TYPE enphase_envoy_up gauge\n\
+             enphase_envoy_up{envoy_serial=\"1\"} 1\n\
+             enphase_envoy_up{envoy_serial=\"2\"} 1\n\
+             # HELP enphase_envoy_phase_watts Current production.\n\
+             # TYPE enphase_envoy_phase_watts gauge\n\
+             enphase_envoy_phase_watts{envoy_serial=\"1\",phase=\"ph-a\"} 1500\n\
+             enphase_envoy_phase_watts{envoy_serial=\"2\",phase=\"ph-a\"} 700\n\
+             # EOF\n"
+        );
+
+        assert_eq!(
+            encoded(vec![envoy(&[], 1500)]),
+            "# HELP enphase_envoy_up Whether the Envoy was reachable.\n\
+             # TYPE enphase_envoy_up gauge\n\
+             enphase_envoy_up 1\n\
+             # HELP enphase_envoy_phase_watts Current production.\n\
+             # TYPE enphase_envoy_phase_watts gauge\n\
+             enphase_envoy_phase_watts{phase=\"ph-a\"} 1500\n\
+             # EOF\n"
+        );
+
+        assert_eq!(encoded(vec![]), "# EOF\n");
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    io::BufReader,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            fs::FileTypeExt,
+            io::{FromRawFd, RawFd},
+            net::UnixDatagram,
+        },
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::future::join_all;
+use hyper_util::{rt::TokioIo, service::TowerToHyperService};
+use prometheus_client::encoding::text::encode;
+use reqwest::{
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    StatusCode,
+};
+use serde_derive::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener},
+    spawn,
+    time::sleep,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    collectors::{coalesced_scrape, latest_scrape},
+    Exporter, Target,
+};
+
+/// Time left for encoding and sending the response before Prometheus gives up.
+const SCRAPE_TIMEOUT_OFFSET: Duration = Duration::from_millis(500);
+
+/// First descriptor passed by systemd with socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+const PROMETHEUS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Settings of the exporter endpoint in the YAML format of the Prometheus
+/// exporter toolkit. Only the settings below are supported, others are rejected
+/// rather than ignored, as ignoring them could leave the exporter less protected.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WebConfig {
+    pub(crate) tls_server_config: Option<TlsServerConfig>,
+    /// Bcrypt hashes of passwords by username.
+    #[serde(default)]
+    pub(crate) basic_auth_users: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsServerConfig {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+}
+
+pub(crate) fn read_web_config(path: &Path) -> Result<WebConfig, String> {
+    let config = fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|config| {
+            serde_norway::from_str::<WebConfig>(&config).map_err(|err| err.to_string())
+        })
+        .and_then(|config| {
+            for (user, hash) in &config.basic_auth_users {
+                if hash.parse::<bcrypt::HashParts>().is_err() {
+                    return Err(format!("password of {user:?} is not a bcrypt hash"));
+                }
+            }
+
+            Ok(config)
+        });
+
+    config.map_err(|err| format!("error reading web config file {}: {err}", path.display()))
+}
+
+pub(crate) fn tls_server_config(config: &TlsServerConfig) -> Result<rustls::ServerConfig, String> {
+    let certs = fs::File::open(&config.cert_file)
+        .and_then(|file| rustls_pemfile::certs(&mut BufReader::new(file)).collect())
+        .map_err(|err| {
+            format!(
+                "error reading certificate from {}: {err}",
+                config.cert_file.display()
+            )
+        })?;
+
+    let key = fs::File::open(&config.key_file)
+        .and_then(|file| rustls_pemfile::private_key(&mut BufReader::new(file)))
+        .map_err(|err| {
+            format!(
+                "error reading key from {}: {err}",
+                config.key_file.display()
+            )
+        })?
+        .ok_or_else(|| format!("no private key in {}", config.key_file.display()))?;
+
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| format!("error loading certificate: {err}"))?;
+
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(server_config)
+}
+
+/// Listening socket, either TCP or a unix socket for `unix:` addresses.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+impl Listener {
+    pub(crate) async fn bind(address: &str) -> std::io::Result<Self> {
+        let Some(path) = address.strip_prefix("unix:") else {
+            return TcpListener::bind(address).await.map(Self::Tcp);
+        };
+
+        // Sockets are left behind when the exporter stops, but other files
+        // are not replaced in case the path is a mistake.
+        if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+
+        UnixListener::bind(path).map(Self::Unix)
+    }
+
+    async fn accept(&self) -> std::io::Result<Box<dyn Connection>> {
+        match self {
+            Self::Tcp(listener) => Ok(Box::new(listener.accept().await?.0)),
+            Self::Unix(listener) => Ok(Box::new(listener.accept().await?.0)),
+        }
+    }
+}
+
+/// Sockets passed by systemd with socket activation, see sd_listen_fds(3).
+/// The variables are removed, so that child processes don't inherit them.
+pub(crate) fn activated_listeners() -> std::io::Result<Vec<Listener>> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+
+    if pid != Some(std::process::id()) {
+        return Ok(vec![]);
+    }
+
+    (0..count)
+        .map(|index| activated_listener(SD_LISTEN_FDS_START + index))
+        .collect()
+}
+
+fn activated_listener(fd: RawFd) -> std::io::Result<Listener> {
+    let mut address = unsafe { std::mem::zeroed::<libc::sockaddr_storage>() };
+    let mut length = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+    let result = unsafe {
+        libc::getsockname(
+            fd,
+            (&mut address as *mut libc::sockaddr_storage).cast(),
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // Descriptors passed by systemd are owned by the process from now on.
+    if address.ss_family as libc::c_int == libc::AF_UNIX {
+        let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        UnixListener::from_std(listener).map(Listener::Unix)
+    } else {
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        TcpListener::from_std(listener).map(Listener::Tcp)
+    }
+}
+
+/// Tells systemd that the exporter is ready once every Envoy was scraped
+/// successfully, so that units with `Type=notify` only start then.
+pub(crate) async fn notify_ready(exporter: Arc<Exporter>) {
+    loop {
+        let targets = exporter.targets();
+
+        let up = join_all(targets.iter().map(|target| async move {
+            let _ = match target.poller {
+                Some(_) => latest_scrape(&target.state).await,
+                None => coalesced_scrape(&target.state, Instant::now(), None).await,
+            };
+
+            target.state.up.get() == 1
+        }))
+        .await;
+
+        let down = up.iter().filter(|up| !**up).count();
+
+        if down == 0 {
+            sd_notify(&format!(
+                "READY=1\nSTATUS=Serving metrics of {} envoys",
+                targets.len()
+            ));
+            return;
+        }
+
+        sd_notify(&format!(
+            "STATUS=Waiting for {down} of {} envoys to be scraped successfully",
+            targets.len()
+        ));
+
+        sleep(Duration::from_secs(10)).await;
+    }
+}
+
+/// Sends a state update to systemd, see sd_notify(3).
+fn sd_notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    let result =
+        UnixDatagram::unbound().and_then(|socket| match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => socket.send_to_addr(
+                state.as_bytes(),
+                &std::os::unix::net::SocketAddr::from_abstract_name(name)?,
+            ),
+            None => socket.send_to(state.as_bytes(), &path),
+        });
+
+    if let Err(err) = result {
+        eprintln!("error notifying systemd: {err}");
+    }
+}
+
+/// Serves the app over TLS or a unix socket, which `axum::serve` doesn't support.
+pub(crate) async fn serve_hyper(listener: Listener, app: Router, acceptor: Option<TlsAcceptor>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok(stream) => stream,
+            Err(err) => {
+                // Usually running out of file descriptors, which takes a while to resolve.
+                eprintln!("error accepting connection: {err}");
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let service = TowerToHyperService::new(app.clone());
+
+        spawn(async move {
+            let stream: Box<dyn Connection> = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => Box::new(stream),
+                    // Failed handshakes are mostly plain HTTP requests and scanners.
+                    Err(_) => return,
+                },
+                None => stream,
+            };
+
+            let _ = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+/// Hash of an empty password to check passwords of unknown users against.
+const DUMMY_BCRYPT_HASH: &str = "$2b$10$RzdH2FWL.vCp4QkLTuKTheppuDctKyit0vpsZDA1Tc2xQxwMPQv12";
+
+/// Users allowed to access the exporter with the bcrypt hashes of their passwords.
+#[derive(Clone)]
+pub(crate) struct BasicAuth {
+    pub(crate) users: Arc<HashMap<String, String>>,
+    /// Bcrypt is slow on purpose, so credentials that were accepted once are
+    /// remembered by their SHA-256 digest instead of being checked on every scrape.
+    pub(crate) verified: Arc<std::sync::Mutex<HashSet<Vec<u8>>>>,
+}
+
+impl BasicAuth {
+    async fn verify(&self, user: &str, password: &str) -> bool {
+        // Usernames can't contain colons, so this is unambiguous.
+        let digest = ring::digest::digest(
+            &ring::digest::SHA256,
+            format!("{user}:{password}").as_bytes(),
+        )
+        .as_ref()
+        .to_vec();
+
+        if self
+            .verified
+            .lock()
+            .expect("verified lock poisoned")
+            .contains(&digest)
+        {
+            return true;
+        }
+
+        let hash = self.users.get(user).cloned();
+        let password = password.to_string();
+
+        // Unknown users are checked against a dummy hash, so that the time
+        // it takes doesn't tell which users exist.
+        let valid = tokio::task::spawn_blocking(move || {
+            bcrypt::verify(&password, hash.as_deref().unwrap_or(DUMMY_BCRYPT_HASH)).unwrap_or(false)
+                && hash.is_some()
+        })
+        .await
+        .unwrap_or(false);
+
+        if valid {
+            self.verified
+                .lock()
+                .expect("verified lock poisoned")
+                .insert(digest);
+        }
+
+        valid
+    }
+}
+
+pub(crate) async fn basic_auth(
+    State(auth): State<BasicAuth>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let credentials = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|value| STANDARD.decode(value).ok())
+        .and_then(|value| String::from_utf8(value).ok());
+
+    let authorized = match credentials
+        .as_deref()
+        .and_then(|value| value.split_once(':'))
+    {
+        Some((user, password)) => auth.verify(user, password).await,
+        None => false,
+    };
+
+    if !authorized {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(WWW_AUTHENTICATE, "Basic")],
+            "Unauthorized\n",
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+pub(crate) async fn healthz() -> &'static str {
+    "ok\n"
+}
+
+pub(crate) async fn readyz(State(exporter): State<Arc<Exporter>>) -> Response {
+    let targets = exporter.targets();
+
+    let problems = targets
+        .iter()
+        .filter_map(Target::not_ready)
+        .collect::<Vec<_>>();
+
+    if problems.is_empty() {
+        return (StatusCode::OK, "ready\n").into_response();
+    }
+
+    (StatusCode::SERVICE_UNAVAILABLE, problems.join("\n") + "\n").into_response()
+}
+
+pub(crate) async fn reload(State(exporter): State<Arc<Exporter>>) -> Response {
+    match exporter.reload().await {
+        Ok(()) => StatusCode::OK.into_response(),
+        Err(err) => {
+            eprintln!("error reloading config: {err}");
+            (StatusCode::INTERNAL_SERVER_ERROR, err).into_response()
+        }
+    }
+}
+
+pub(crate) async fn metrics(State(exporter): State<Arc<Exporter>>, headers: HeaderMap) -> Response {
+    let targets = exporter.targets();
+
+    let requested = Instant::now();
+
+    let deadline = headers
+        .get("x-prometheus-scrape-timeout-seconds")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .map(|timeout| requested + timeout.saturating_sub(SCRAPE_TIMEOUT_OFFSET));
+
+    let results = join_all(targets.iter().map(|target| async move {
+        match target.poller {
+            Some(_) => latest_scrape(&target.state).await,
+            None => coalesced_scrape(&target.state, requested, deadline).await,
+        }
+    }))
+    .await;
+
+    if let Err(err) = results.into_iter().collect::<Result<Vec<_>, _>>() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, err).into_response();
+    }
+
+    let mut buffer = String::new();
+
+    if let Err(err) = encode(&mut buffer, &exporter.registry()) {
+        eprintln!("error encoding metrics: {err}");
+        exporter.encoding_errors.inc();
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "error encoding metrics\n",
+        )
+            .into_response();
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "content-type",
+        HeaderValue::from_static(PROMETHEUS_CONTENT_TYPE),
+    );
+
+    (headers, buffer).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn web_config_yaml() {
+        let config = serde_norway::from_str::<WebConfig>(
+            "tls_server_config:\n  cert_file: /etc/exporter/cert.pem\n  key_file: /etc/exporter/key.pem\n\
+             basic_auth_users:\n  alice: $2y$10$X0h1gDsPszWURQaxFh.zoubFi6DXncSjhoQNJgRrnGs7EsimhC7zG\n",
+        )
+        .unwrap();
+
+        assert!(config.tls_server_config.is_some());
+        assert_eq!(config.basic_auth_users.len(), 1);
+
+        assert!(
+            serde_norway::from_str::<WebConfig>("http_server_config:\n  http2: false\n").is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn basic_auth_users() {
+        let auth = BasicAuth {
+            users: Arc::new(HashMap::from([(
+                "alice".to_string(),
+                bcrypt::hash("secret", 4).unwrap(),
+            )])),
+            verified: Arc::default(),
+        };
+
+        assert!(!auth.verify("alice", "wrong").await);
+        assert!(!auth.verify("bob", "secret").await);
+        assert!(auth.verify("alice", "secret").await);
+
+        assert_eq!(auth.verified.lock().unwrap().len(), 1);
+        assert!(auth.verify("alice", "secret").await);
+    }
+}